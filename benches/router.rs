@@ -0,0 +1,88 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use mqtt_macro::{MqttDeserializeError, MqttItem, Topic, TopicRouter};
+
+#[derive(MqttItem, Debug)]
+enum Device {
+    #[mqtt_item(topic = "device/<0>/temperature", payload = "<1>")]
+    Temperature(u32, f32),
+    #[mqtt_item(topic = "device/<0>/humidity", payload = "<1>")]
+    Humidity(u32, f32),
+    #[mqtt_item(topic = "device/<0>/pressure", payload = "<1>")]
+    Pressure(u32, f32),
+    #[mqtt_item(topic = "device/<0>/battery", payload = "<1>")]
+    Battery(u32, u8),
+    #[mqtt_item(topic = "device/<0>/online")]
+    Online(u32),
+    #[mqtt_item(topic = "device/<0>/firmware/version", payload = "<1>")]
+    FirmwareVersion(u32, String),
+}
+
+#[derive(MqttItem, Debug)]
+enum Gateway {
+    #[mqtt_item(topic = "gateway/<0>/uptime", payload = "<1>")]
+    Uptime(u32, u64),
+    #[mqtt_item(topic = "gateway/<0>/clients", payload = "<1>")]
+    Clients(u32, u32),
+    #[mqtt_item(topic = "gateway/<0>/online")]
+    Online(u32),
+}
+
+#[derive(MqttItem, Debug)]
+enum Command {
+    #[mqtt_item(topic = "cmd/<0>/reboot")]
+    Reboot(u32),
+    #[mqtt_item(topic = "cmd/<0>/config", payload = "<1>")]
+    Config(u32, Vec<u32>),
+}
+
+// The items are only dispatched, never read
+#[allow(dead_code)]
+#[derive(Debug)]
+enum Incoming {
+    Device(Device),
+    Gateway(Gateway),
+    Command(Command),
+}
+
+/// Try each item type in sequence, like a hand-written dispatcher without a router would
+fn dispatch_sequential(topic: &Topic, payload: &[u8]) -> Result<Incoming, MqttDeserializeError> {
+    if let Ok(item) = Device::from_topic_and_payload(topic.clone(), payload) {
+        return Ok(Incoming::Device(item));
+    }
+    if let Ok(item) = Gateway::from_topic_and_payload(topic.clone(), payload) {
+        return Ok(Incoming::Gateway(item));
+    }
+    Command::from_topic_and_payload(topic.clone(), payload).map(Incoming::Command)
+}
+
+fn dispatch(c: &mut Criterion) {
+    let mut router: TopicRouter<Incoming> = TopicRouter::new();
+    router
+        .add(Incoming::Device)
+        .add(Incoming::Gateway)
+        .add(Incoming::Command);
+
+    let messages = [
+        ("first", Topic::from_str("device/12/temperature"), "21.5"),
+        (
+            "longest",
+            Topic::from_str("device/12/firmware/version"),
+            "\"1.2.0\"",
+        ),
+        ("last", Topic::from_str("cmd/12/config"), "[1,2,3]"),
+    ];
+
+    for (name, topic, payload) in &messages {
+        let mut group = c.benchmark_group(*name);
+        group.bench_function("sequential", |b| {
+            b.iter(|| dispatch_sequential(black_box(topic), black_box(payload.as_bytes())))
+        });
+        group.bench_function("router", |b| {
+            b.iter(|| router.dispatch(black_box(topic), black_box(payload.as_bytes())))
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
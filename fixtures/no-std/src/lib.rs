@@ -0,0 +1,37 @@
+//! Items that derive `MqttItem` and `MqttItemRef` in a `no_std` crate, so that CI checks that the
+//! derived code compiles for embedded targets such as `thumbv7em-none-eabihf`.
+#![no_std]
+
+use mqtt_macro::{MqttItem, MqttItemRef};
+
+#[derive(MqttItem, MqttItemRef, Debug, PartialEq)]
+pub enum Sensor {
+    #[mqtt_item(topic = "sensor/<id>/temp", payload = "<value>")]
+    Temperature { id: u32, value: f32 },
+    #[mqtt_item(topic = "sensor/<0>/reading", payload = "{<1>, <2>}")]
+    Reading(u32, f32, u8),
+    #[mqtt_item(topic = "sensor/<0>/state", payload = "<1>", payload_format = "text")]
+    State(u32, bool),
+    #[mqtt_item(topic = "sensor/reset")]
+    Reset,
+}
+
+#[derive(MqttItem, MqttItemRef, Debug, PartialEq)]
+#[mqtt_item(topic = "node/<node>/<sensor>")]
+pub struct Node {
+    pub node: u16,
+    #[mqtt_item(layer)]
+    pub sensor: Sensor,
+}
+
+#[derive(MqttItemRef, Debug, PartialEq)]
+#[mqtt_item(
+    topic = "log/<device>/<level>",
+    payload = "<line>",
+    payload_format = "raw"
+)]
+pub struct Log<'a> {
+    pub device: &'a str,
+    pub level: u8,
+    pub line: &'a str,
+}
@@ -1,114 +1,217 @@
-//! Implementation of enum derive macro for MqttItem
-
-use std::collections::HashSet;
+//! Implementation of enum derive macros for MqttItem and MqttItemRef
 
 use proc_macro2::{Span, TokenStream};
 use proc_macro_error::abort;
 use quote::quote;
-use syn::{spanned::Spanned, Ident, Index, ItemEnum, Member, Type, Variant};
-
-use crate::{get_attribute_list, get_kv, get_mqtt_attribute, Topic, TopicPart};
-
-#[derive(PartialEq, Clone, Debug)]
-struct IdentifiedField {
-    ty: Type,
-    ident: Ident,
-    name: String,
-}
-
-#[derive(Clone, Copy, PartialEq)]
-enum FieldType {
-    Unnamed,
-    Named,
+use syn::{spanned::Spanned, Ident, ItemEnum, Type, Variant};
+
+use crate::{
+    item_impl::{
+        generate_generic_topics, generate_item_impl, to_topic_and_payload_generics, ItemImpl,
+    },
+    ref_generics, ContainerAttributes, Topic,
+};
+
+/// Abort if the topic of `variant` collides with the topic of one of the previous variants
+fn check_topic_collision(
+    topics: &[(String, Topic, Option<Type>)],
+    topic: &Topic,
+    variant: &Variant,
+) {
+    if let Some(data) = topics.iter().find(|(_, t, _)| t == topic) {
+        abort!(
+            variant.span(),
+            "Variants `{}` and `{}` have the same topic filter!",
+            data.0,
+            variant.ident.to_string()
+        );
+    }
 }
 
-fn find_field<'b, T: Spanned>(
-    field_type: FieldType,
-    on: &T,
-    fields: &'b [IdentifiedField],
-    path: &String,
-) -> &'b IdentifiedField {
-    if field_type == FieldType::Unnamed {
-        let idx = if let Ok(val) = path.parse::<u32>() {
-            val
-        } else {
-            abort!(
-                on.span(),
-                "Invalid identifier {}. Must be decimal field name for tuple variants",
-                path
-            )
-        };
-
-        if let Some(field) = fields.iter().find(|f| f.name == format!("{}", idx)) {
-            field
-        } else {
-            abort!(on.span(), "Unknown field {}", path);
-        }
-    } else {
-        if let Some(field) = fields.iter().find(|f| &f.name == path) {
-            field
-        } else {
-            abort!(on.span(), "Unknown field {}", path);
+/// Generate the attempt to parse a variant with the parse function `parse_fn_name`, as part of
+/// parsing an enum. Topic mismatches are collected in `errors`, other errors are returned.
+fn parse_variant(
+    crate_name: &TokenStream,
+    parse_fn_name: &Ident,
+    variant_name: &str,
+    layers: TokenStream,
+) -> TokenStream {
+    quote! {
+        match Self::#parse_fn_name(#layers, payload) {
+            Ok(field) => return Ok(field),
+            Err(#crate_name::ParseError::TopicMismatch(error)) => errors.push(#crate_name::VariantError {
+                variant: #variant_name,
+                error,
+            }),
+            // The topic belongs to this variant, so the payload is invalid
+            Err(error) => return Err(error),
         }
     }
 }
 
-pub fn impl_for_enum(crate_name: &TokenStream, en: &ItemEnum) -> TokenStream {
+pub fn impl_for_enum(
+    crate_name: &TokenStream,
+    container: &ContainerAttributes,
+    en: &ItemEnum,
+) -> TokenStream {
     let en_ident = &en.ident;
     let (imp, ty, wh) = en.generics.split_for_impl();
+    let ContainerAttributes {
+        deserialize_error_type,
+        serialize_error_type,
+        ..
+    } = container;
 
     let mut functions = Vec::new();
     let mut generator = Vec::new();
-    let mut topics: Vec<(String, Topic, bool)> = Vec::new();
+    let mut topics: Vec<(String, Topic, Option<Type>)> = Vec::new();
+    let mut index_parse = Vec::new();
+    let mut variant_size_hint = Vec::new();
+    // The end of the range of generic topics of the previous variant
+    let mut generic_topics_end = quote! { 0 };
 
     for variant in &en.variants {
-        let (push, (parse_fn_name, parse_fn), fields, topic_len, topic, has_payload) =
-            generate_variant_impl(crate_name, &en_ident, variant);
-
-        if let Some(data) = topics.iter().find(|(v, t, p)| *t == topic) {
-            abort!(
-                variant.span(),
-                "Variants `{}` and `{}` have the same topic filter!",
-                data.0,
-                variant.ident.to_string()
-            );
-        }
+        let variant_ident = &variant.ident;
+        let path = quote! { #en_ident::#variant_ident };
+
+        let ItemImpl {
+            pattern,
+            push,
+            push_ref,
+            write,
+            size_hint,
+            parse,
+            topic,
+            nested,
+            ..
+        } = generate_item_impl(
+            crate_name,
+            container,
+            &path,
+            variant_ident,
+            &variant.attrs,
+            &variant.fields,
+            &en.generics,
+            variant.span(),
+            None,
+        );
+
+        check_topic_collision(&topics, &topic, variant);
 
-        topics.push((variant.ident.to_string(), topic, has_payload));
+        // Topics that capture the remaining layers are attempted after all others
+        let topic_len = (!topic.has_rest(), topic.iter().count());
+
+        let variant_name = variant.ident.to_string();
+        let parse_fn_name = Ident::new(
+            ("__mqttitem__parse_".to_string() + variant.ident.to_string().as_str()).as_str(),
+            Span::call_site(),
+        );
+
+        // A variant with a nested item has as many generic topics as the nested item
+        let generic_topics_len = match &nested {
+            Some(ty) => quote! { <#ty as #crate_name::MqttItem>::all_generic_topics().len() },
+            None => quote! { 1 },
+        };
+        generic_topics_end = quote! { #generic_topics_end + #generic_topics_len };
+        index_parse.push(quote! {
+            if index < #generic_topics_end {
+                return Self::#parse_fn_name(topic.layers(), payload).map_err(#crate_name::ParseError::into_error);
+            }
+        });
 
-        let variant = variant.ident.clone();
+        topics.push((variant.ident.to_string(), topic, nested));
 
         functions.push(quote! {
-            #parse_fn
+            fn #parse_fn_name<'__topic>(mut topic: impl Iterator<Item = &'__topic str>, payload: &[u8]) -> Result<Self, #crate_name::ParseError<#deserialize_error_type>> {
+                #parse
+                Ok(#pattern)
+            }
+        });
+
+        variant_size_hint.push(quote! {
+            #[allow(unused_variables)]
+            #pattern => #size_hint,
         });
 
         generator.push((
+            parse_variant(
+                crate_name,
+                &parse_fn_name,
+                &variant_name,
+                quote! { topic.layers() },
+            ),
+            quote! {
+                #pattern => {
+                    #push
+                    Ok(())
+                }
+            },
             quote! {
-                if let Ok(field) = Self::#parse_fn_name(topic.layers(), payload) {
-                    return Ok(field);
+                #pattern => {
+                    #push_ref
+                    Ok(())
                 }
             },
             quote! {
-                #push
+                #pattern => {
+                    #write
+                    Ok(())
+                }
             },
             topic_len,
         ));
     }
 
-    // Make sure that we always attempt to parse the longest topic first
-    generator.sort_by(|a, b| b.2.cmp(&a.2));
+    // Make sure that we always attempt to parse the longest topic first, and those that capture
+    // the remaining layers last
+    generator
+        .sort_by_key(|(_parse, _push, _push_ref, _write, topic_len)| std::cmp::Reverse(*topic_len));
 
     let variant_parse: Vec<&TokenStream> = generator
         .iter()
-        .map(|(parse, _push, _topic_len)| parse)
+        .map(|(parse, _push, _push_ref, _write, _topic_len)| parse)
         .collect();
 
     let variant_push: Vec<&TokenStream> = generator
         .iter()
-        .map(|(_parse, push, _topic_len)| push)
+        .map(|(_parse, push, _push_ref, _write, _topic_len)| push)
+        .collect();
+
+    let variant_push_ref: Vec<&TokenStream> = generator
+        .iter()
+        .map(|(_parse, _push, push_ref, _write, _topic_len)| push_ref)
         .collect();
 
-    let generic_topicstr: Vec<String> = topics.iter().map(|t| t.1.filter_string()).collect();
+    let variant_write: Vec<&TokenStream> = generator
+        .iter()
+        .map(|(_parse, _push, _push_ref, write, _topic_len)| write)
+        .collect();
+
+    let generic_topics: Vec<(&Topic, Option<&Type>)> = topics
+        .iter()
+        .map(|(_, topic, nested)| (topic, nested.as_ref()))
+        .collect();
+    let generic_topics =
+        generate_generic_topics(crate_name, &generic_topics, &en.generics, en.span());
+
+    let to_topic_and_payload = to_topic_and_payload_generics(
+        crate_name,
+        container,
+        &en.generics,
+        topics.iter().filter_map(|(_, _, nested)| nested.as_ref()),
+    )
+    .map(|generics| {
+        let (_, _, ref_wh) = generics.split_for_impl();
+        quote! {
+            impl #imp #crate_name::ToTopicAndPayload for #en_ident #ty #ref_wh {
+                fn push_topic_and_payload_ref(&self, topic: &mut #crate_name::Topic, payload: &mut #crate_name::__alloc::Vec<u8>) -> Result<(), Self::SerializeError> {
+                    match self {
+                        #(#variant_push_ref)*
+                    }
+                }
+            }
+        }
+    });
 
     quote! {
 
@@ -117,305 +220,178 @@ pub fn impl_for_enum(crate_name: &TokenStream, en: &ItemEnum) -> TokenStream {
         }
 
         impl #imp #crate_name::MqttItem for #en_ident #ty #wh {
-            type DeserializeError = #crate_name::MqttDeserializeError;
-            type SerializeError = ::serde_json::Error;
+            type DeserializeError = #deserialize_error_type;
+            type SerializeError = #serialize_error_type;
 
-            fn push_topic_and_payload(self, topic: &mut #crate_name::Topic, payload: &mut Vec<u8>) -> Result<(), Self::SerializeError> {
+            fn push_topic_and_payload(self, topic: &mut #crate_name::Topic, payload: &mut #crate_name::__alloc::Vec<u8>) -> Result<(), Self::SerializeError> {
                 match self {
                     #(#variant_push)*
                 }
             }
 
+            fn write_topic_and_payload<__TopicWriter, __PayloadWriter>(
+                self,
+                topic: &mut __TopicWriter,
+                payload: &mut __PayloadWriter,
+            ) -> Result<(), Self::SerializeError>
+            where
+                __TopicWriter: #crate_name::TopicWriter + ?Sized,
+                __PayloadWriter: #crate_name::PayloadWriter + ?Sized,
+                Self::SerializeError: From<#crate_name::MqttSerializeError>,
+            {
+                #crate_name::write_or_restore_topic(topic, |topic| -> Result<(), Self::SerializeError> {
+                    match self {
+                        #(#variant_write)*
+                    }
+                })
+            }
+
+            fn payload_size_hint(&self) -> Option<usize> {
+                match self {
+                    #(#variant_size_hint)*
+                }
+            }
+
             fn from_topic_and_payload<'a>(
                 topic: #crate_name::Topic,
                 payload: &'a [u8],
             ) -> Result<Self, Self::DeserializeError> {
+                Self::parse_topic_and_payload(topic, payload).map_err(#crate_name::ParseError::into_error)
+            }
+
+            fn parse_topic_and_payload(
+                topic: #crate_name::Topic,
+                payload: &[u8],
+            ) -> Result<Self, #crate_name::ParseError<Self::DeserializeError>> {
+                let mut errors = #crate_name::__alloc::Vec::new();
                 #(#variant_parse)*
-                Err(Self::DeserializeError::Invalid)
+                Err(#crate_name::ParseError::TopicMismatch(
+                    #crate_name::MqttDeserializeError::NoMatchingTopic(errors),
+                ))
+            }
+
+            fn from_generic_topic_and_payload(
+                index: usize,
+                topic: &#crate_name::Topic,
+                payload: &[u8],
+            ) -> Result<Self, Self::DeserializeError> {
+                #(#index_parse)*
+                Err(#crate_name::MqttDeserializeError::Invalid.into())
             }
 
             fn all_generic_topics() -> &'static [&'static str] {
-                &[#(#generic_topicstr ,)*]
+                #generic_topics
             }
         }
 
+        #to_topic_and_payload
+
     }
 }
 
-fn generate_variant_impl(
+pub fn impl_ref_for_enum(
     crate_name: &TokenStream,
-    enum_name: &Ident,
-    variant: &Variant,
-) -> (
-    TokenStream,
-    (Ident, TokenStream),
-    Vec<IdentifiedField>,
-    usize,
-    Topic,
-    bool,
-) {
-    let meta_list = if let Some(attr) = get_mqtt_attribute(&variant.attrs) {
-        if let Some(list) = get_attribute_list(&attr) {
-            list.nested
-        } else {
-            abort!(variant, "The `mqtt_item` does not have a meta list");
-        }
-    } else {
-        abort!(
-            variant,
-            "This variant does not have an `mqtt_item` attribute"
-        );
-    };
-
-    let (mut fields, field_type): (Vec<IdentifiedField>, _) = match &variant.fields {
-        syn::Fields::Unnamed(fields) => (
-            fields
-                .unnamed
-                .iter()
-                .enumerate()
-                .map(|(idx, f)| IdentifiedField {
-                    ty: f.ty.clone(),
-                    ident: Ident::new(&format!("_mqttitem_field_field_{}", idx), variant.span()),
-                    name: format!("{}", idx),
-                })
-                .collect(),
-            FieldType::Unnamed,
-        ),
-        syn::Fields::Named(fields) => (
-            fields
-                .named
-                .iter()
-                .map(|f| IdentifiedField {
-                    ty: f.ty.clone(),
-                    ident: Ident::new(
-                        &format!("_mqttitem_field_{}", f.ident.clone().unwrap()),
-                        variant.span(),
-                    ),
-                    name: f.ident.clone().unwrap().to_string(),
-                })
-                .collect(),
-            FieldType::Named,
-        ),
-        _ => abort!(
-            variant,
-            "Unit variants are not supported. Try `{}()` instead",
-            variant.ident.to_string()
-        ),
-    };
-
-    let mut not_processed_fields = fields.clone();
-    let mut process_field = |name: &String| {
-        let index = if let Some(idx) = not_processed_fields.iter().position(|f| &f.name == name) {
-            idx
-        } else {
-            abort!(
-                variant.span(),
-                format!("Field `{}` is specified more than once", name)
-            );
-        };
-        not_processed_fields.remove(index);
-    };
-
-    let topic = if let Some(topic) = get_kv(&meta_list, "topic") {
-        let topic_lit = match topic {
-            syn::Lit::Str(str) => str.value(),
-            _ => abort!(variant, "The topic must be a literal string."),
-        };
-        Topic::from_string(&topic_lit, variant)
-    } else {
-        abort!(variant, "Topic not defined");
-    };
-
-    let topic_len = topic.iter().count();
-
-    let payload = if let Some(payload) = get_kv(&meta_list, "payload") {
-        match payload {
-            syn::Lit::Str(str) => {
-                let value = str.value();
-                if value.starts_with('<') && value.ends_with('>') {
-                    Some(value[1..value.len() - 1].to_string())
-                } else {
-                    abort!(variant, "Payload field must be written as <field_name>");
-                }
-            }
-            _ => abort!(variant, "Payload field not found"),
-        }
-    } else {
-        None
-    };
-    // Override_serialize
-    let override_serialization = if let Some(override_ser) = get_kv(&meta_list, "serialize_using") {
-        match override_ser {
-            syn::Lit::Str(str) => {
-                let value = str.value();
-                Some(value)
-            }
-            _ => abort!(variant, "Serialization function not found in derive"),
-        }
-    } else {
-        None
-    };
-
-    // Override_deserialize
-    let override_deserialization =
-        if let Some(override_deser) = get_kv(&meta_list, "deserialize_using") {
-            match override_deser {
-                syn::Lit::Str(str) => {
-                    let value = str.value();
-                    Some(value)
-                }
-                _ => abort!(variant, "Deserialization function not found in derive"),
-            }
-        } else {
-            None
-        };
-
-    let (payload_serialize, payload_deserialize) = if let Some(payload) = &payload {
-        let IdentifiedField { ty, ident, name } =
-            find_field(field_type, &variant, &fields, &payload);
-        process_field(name);
+    container: &ContainerAttributes,
+    en: &ItemEnum,
+) -> TokenStream {
+    let en_ident = &en.ident;
+    let (lifetime, generics) = ref_generics(&en.generics, en.span());
+    let (imp, _, _) = generics.split_for_impl();
+    let (_, ty, wh) = en.generics.split_for_impl();
+    let deserialize_error_type = &container.deserialize_error_type;
 
-        let ser = if let Some(override_fun) = override_serialization {
-            let override_fun: TokenStream = override_fun.parse().unwrap();
-            quote! {
-                #override_fun(payload, &#ident)?;
-            }
-        } else {
-            quote! {
-                #crate_name::serde_json_serialize(payload, &#ident)?;
-            }
-        };
-        let deser = if let Some(override_fun) = override_deserialization {
-            let override_fun: TokenStream = override_fun.parse().unwrap();
-            quote! {
-                let #ident = #override_fun(payload)?;
-            }
-        } else {
-            quote! {
-                let #ident: #ty = #crate_name::serde_json_deserialize(payload)?;
-            }
-        };
+    let mut functions = Vec::new();
+    let mut variant_parse = Vec::new();
+    let mut topics: Vec<(String, Topic, Option<Type>)> = Vec::new();
 
-        (Some(ser), Some(deser))
-    } else {
-        (None, None)
-    };
+    for variant in &en.variants {
+        let variant_ident = &variant.ident;
+        let path = quote! { #en_ident::#variant_ident };
+
+        let ItemImpl {
+            pattern,
+            parse,
+            topic,
+            nested,
+            ..
+        } = generate_item_impl(
+            crate_name,
+            container,
+            &path,
+            variant_ident,
+            &variant.attrs,
+            &variant.fields,
+            &generics,
+            variant.span(),
+            Some(&lifetime),
+        );
 
-    let mut topic_push = Vec::new();
-    let mut topic_parse = Vec::new();
+        check_topic_collision(&topics, &topic, variant);
 
-    for topic_layer in topic.parts.iter() {
-        let push = match topic_layer {
-            TopicPart::Ident(ident) => {
-                let IdentifiedField { ty, ident, name } =
-                    find_field(field_type, &variant, &fields, ident);
-                process_field(name);
+        let topic_len = (!topic.has_rest(), topic.iter().count());
+        topics.push((variant.ident.to_string(), topic, nested));
 
-                quote! {
-                    topic.push(&#ident.to_string());
-                }
-            }
-            TopicPart::Literal(literal) => {
-                quote! {
-                    topic.push(#literal.into());
-                }
-            }
-        };
+        let variant_name = variant.ident.to_string();
+        let parse_fn_name = Ident::new(
+            &format!("__mqttitem__parse_ref_{}", variant_name),
+            Span::call_site(),
+        );
 
-        topic_push.push(push);
-
-        let parse = match topic_layer {
-            TopicPart::Ident(ident) => {
-                let IdentifiedField { ty, ident, name } =
-                    find_field(field_type, &variant, &fields, ident);
-                quote! {
-                   let #ident = if let Some(value) = topic.next() {
-                        value
-                            .parse()
-                            .map_err(|_| {
-                                <Self as #crate_name::MqttItem>::DeserializeError::InvalidTopicLayer(#name.into(), value.into())
-                            })?
-                    } else {
-                        return Err(<Self as #crate_name::MqttItem>::DeserializeError::MissingTopicLayer(#name.into()));
-                    };
-                }
-            }
-            TopicPart::Literal(literal) => {
-                quote! {
-                    if let Some(value) = topic.next() {
-                        if value != #literal.to_string() {
-                            return Err(<Self as #crate_name::MqttItem>::DeserializeError::MissingTopicLayer(#literal.into()));
-                        }
-                    } else {
-                        return Err(<Self as #crate_name::MqttItem>::DeserializeError::MissingTopicLayer(#literal.into()));
-                    }
-                }
+        functions.push(quote! {
+            fn #parse_fn_name(mut topic: impl Iterator<Item = &#lifetime str> + Clone, payload: &#lifetime [u8]) -> Result<Self, #crate_name::ParseError<#deserialize_error_type>> {
+                #parse
+                Ok(#pattern)
             }
-        };
+        });
 
-        topic_parse.push(parse);
+        variant_parse.push((
+            parse_variant(
+                crate_name,
+                &parse_fn_name,
+                &variant_name,
+                quote! { layers.clone() },
+            ),
+            topic_len,
+        ));
     }
 
-    if !not_processed_fields.is_empty() {
-        let mut msg = format!("The following fields are not part of the topic or payload: ");
-        for field in not_processed_fields.iter() {
-            msg = format!("{}{}, ", msg, field.name)
-        }
-        abort!(variant, msg);
-    }
+    // The variants are attempted in the same order as those of `MqttItem::from_topic_and_payload`
+    variant_parse.sort_by_key(|(_parse, topic_len)| std::cmp::Reverse(*topic_len));
+    let variant_parse = variant_parse.iter().map(|(parse, _topic_len)| parse);
 
-    let variant_name = variant.ident.clone();
+    quote! {
 
-    let field_names: Vec<TokenStream> = fields
-        .iter()
-        .map(|f| {
-            let ident = f.ident.clone();
-            if field_type == FieldType::Unnamed {
-                quote! {
-                    #ident
-                }
-            } else {
-                let name = Ident::new(&f.name, variant.span());
-                quote! {
-                    #name: #ident
-                }
+        impl #imp #en_ident #ty #wh {
+            #(#functions)*
+        }
+
+        impl #imp #crate_name::MqttItemRef<#lifetime> for #en_ident #ty #wh {
+            type DeserializeError = #deserialize_error_type;
+
+            fn from_layers_and_payload<I>(
+                layers: I,
+                payload: &#lifetime [u8],
+            ) -> Result<Self, Self::DeserializeError>
+            where
+                I: Iterator<Item = &#lifetime str> + Clone,
+            {
+                Self::parse_layers_and_payload(layers, payload).map_err(#crate_name::ParseError::into_error)
             }
-        })
-        .collect();
 
-    let fields_stmt = if FieldType::Named == field_type {
-        quote! { #enum_name::#variant_name { #(#field_names,)* } }
-    } else {
-        quote! { #enum_name::#variant_name ( #(#field_names,)* ) }
-    };
-
-    let push = quote! {
-        #fields_stmt => {
-            #(#topic_push)*
-            #payload_serialize
-            Ok(())
-        }
-    };
-
-    let parse_fn_name = Ident::new(
-        ("__mqttitem__parse_".to_string() + variant.ident.to_string().as_str()).as_str(),
-        Span::call_site(),
-    );
-
-    let parse_fn = quote! {
-        fn #parse_fn_name<'__topic>(mut topic: impl Iterator<Item = &'__topic str>, payload: &[u8]) -> Result<Self, <Self as #crate_name::MqttItem>::DeserializeError> {
-            #(#topic_parse)*
-            #payload_deserialize
-            Ok(#fields_stmt)
+            fn parse_layers_and_payload<I>(
+                layers: I,
+                payload: &#lifetime [u8],
+            ) -> Result<Self, #crate_name::ParseError<Self::DeserializeError>>
+            where
+                I: Iterator<Item = &#lifetime str> + Clone,
+            {
+                let mut errors = #crate_name::__alloc::Vec::new();
+                #(#variant_parse)*
+                Err(#crate_name::ParseError::TopicMismatch(
+                    #crate_name::MqttDeserializeError::NoMatchingTopic(errors),
+                ))
+            }
         }
-    };
-
-    (
-        push,
-        (parse_fn_name, parse_fn),
-        fields,
-        topic_len,
-        topic,
-        payload.is_some(),
-    )
+
+    }
 }
@@ -0,0 +1,1023 @@
+//! Generation of the topic and payload (de)serialization of a single MqttItem, shared by
+//! enum variants and structs
+
+use proc_macro2::{Span, TokenStream};
+use proc_macro_error::abort;
+use quote::{quote, ToTokens};
+use syn::{
+    parse_quote, Attribute, Fields, GenericParam, Generics, Ident, Lifetime, LifetimeDef, Type,
+};
+
+use crate::{
+    attribute_flag_set, get_attribute_list, get_codec, get_kv, get_mqtt_attribute,
+    ContainerAttributes, Topic, TopicPart,
+};
+
+#[derive(PartialEq, Clone, Debug)]
+pub(crate) struct IdentifiedField {
+    pub ty: Type,
+    pub ident: Ident,
+    pub name: String,
+    /// Whether this field is marked with `#[mqtt_item(extend_into)]`
+    pub extend_into: bool,
+    /// Whether this field is marked with `#[mqtt_item(layer)]`
+    pub layer: bool,
+    /// Whether this field is marked with `#[mqtt_item(payload)]`
+    pub payload: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum FieldType {
+    Unnamed,
+    Named,
+    Unit,
+}
+
+/// The function used to serialize or deserialize a payload
+enum PayloadFunction {
+    /// A `serialize_using` or `deserialize_using` function
+    Using(TokenStream),
+    /// A type implementing `PayloadCodec`
+    Codec(TokenStream),
+}
+
+impl PayloadFunction {
+    /// Select the function that takes precedence, falling back to `JsonCodec`
+    fn select(
+        using: Option<TokenStream>,
+        codec: Option<TokenStream>,
+        container_using: Option<TokenStream>,
+        container_codec: Option<TokenStream>,
+        crate_name: &TokenStream,
+    ) -> Self {
+        using
+            .map(Self::Using)
+            .or_else(|| codec.map(Self::Codec))
+            .or_else(|| container_using.map(Self::Using))
+            .or_else(|| container_codec.map(Self::Codec))
+            .unwrap_or_else(|| Self::Codec(quote! { #crate_name::JsonCodec }))
+    }
+
+    /// Generate the statement that writes `value`, of type `ty`, to the `payload` writer
+    fn write(
+        &self,
+        crate_name: &TokenStream,
+        ty: impl ToTokens,
+        value: TokenStream,
+    ) -> TokenStream {
+        match self {
+            // Functions can only serialize into a `Vec`
+            Self::Using(override_fun) => quote! {
+                let mut buffer = #crate_name::__alloc::Vec::new();
+                #override_fun(&mut buffer, #value)?;
+                #crate_name::PayloadWriter::write_bytes(payload, &buffer)?;
+            },
+            Self::Codec(codec) => quote! {
+                <#codec as #crate_name::PayloadCodec<#ty>>::write(payload, #value)?;
+            },
+        }
+    }
+
+    /// Generate the expression that evaluates to the serialized length of `value`, of type `ty`, if
+    /// it is known
+    fn size_hint(
+        &self,
+        crate_name: &TokenStream,
+        ty: impl ToTokens,
+        value: TokenStream,
+    ) -> TokenStream {
+        match self {
+            // Functions can only serialize into a `Vec`, so their length is not known beforehand
+            Self::Using(_) => quote! { None },
+            Self::Codec(codec) => quote! {
+                <#codec as #crate_name::PayloadCodec<#ty>>::size_hint(#value)
+            },
+        }
+    }
+}
+
+/// The fields that make up the payload of an item
+enum Payload {
+    /// A single field, which is (de)serialized as the payload
+    Single(String),
+    /// Multiple fields, which are (de)serialized together as a map of field names to values
+    Fields(Vec<String>),
+}
+
+impl Payload {
+    /// Parse a `<field_name>` or `{<field_name>, <other_field_name>}` payload
+    fn from_string(input: &str, span: Span) -> Self {
+        let field_name = |value: &str| {
+            let value = value.trim();
+            if value.starts_with('<') && value.ends_with('>') {
+                value[1..value.len() - 1].to_string()
+            } else {
+                abort!(span, "Payload field must be written as <field_name>");
+            }
+        };
+
+        if input.starts_with('{') && input.ends_with('}') {
+            let fields = input[1..input.len() - 1]
+                .split(',')
+                .filter(|field| !field.trim().is_empty())
+                .map(field_name)
+                .collect();
+            Payload::Fields(fields)
+        } else {
+            Payload::Single(field_name(input))
+        }
+    }
+}
+
+/// The generated code for a single item (an enum variant, or a struct)
+pub(crate) struct ItemImpl {
+    /// The pattern that destructures (and constructs) this item, binding all of its fields
+    pub pattern: TokenStream,
+    /// Statements that push the topic and payload of the fields bound by `pattern` into `topic`
+    /// and `payload`
+    pub push: TokenStream,
+    /// Statements that push the topic and payload of the fields bound by reference by `pattern`
+    /// into `topic` and `payload`
+    pub push_ref: TokenStream,
+    /// Statements that write the topic and payload of the fields bound by `pattern`
+    /// to the `topic` and `payload` writers
+    pub write: TokenStream,
+    /// Expression that evaluates to the length of the payload of the fields bound by reference
+    /// by `pattern`
+    pub size_hint: TokenStream,
+    /// Statements that parse this item from the `topic` layer iterator and `payload`, returning
+    /// a `ParseError` of the `DeserializeError` of the item on failure
+    pub parse: TokenStream,
+    /// The topic of this item
+    pub topic: Topic,
+    /// Whether this item has a payload
+    pub has_payload: bool,
+    /// The type of the nested `MqttItem` field of this item, if any
+    pub nested: Option<Type>,
+}
+
+fn find_field<'b>(
+    field_type: FieldType,
+    span: Span,
+    fields: &'b [IdentifiedField],
+    path: &str,
+) -> &'b IdentifiedField {
+    if field_type == FieldType::Unnamed {
+        let idx = if let Ok(val) = path.parse::<u32>() {
+            val
+        } else {
+            abort!(
+                span,
+                "Invalid identifier {}. Must be decimal field name for tuple variants",
+                path
+            )
+        };
+
+        if let Some(field) = fields.iter().find(|f| f.name == format!("{}", idx)) {
+            field
+        } else {
+            abort!(span, "Unknown field {}", path);
+        }
+    } else if let Some(field) = fields.iter().find(|f| f.name == path) {
+        field
+    } else {
+        abort!(span, "Unknown field {}", path);
+    }
+}
+
+/// Whether `ty` is `&str`, which borrowed items can bind topic layers to directly
+fn is_str_ref(ty: &Type) -> bool {
+    match ty {
+        Type::Reference(reference) => {
+            matches!(&*reference.elem, Type::Path(path) if path.path.is_ident("str"))
+        }
+        _ => false,
+    }
+}
+
+/// Generate the topic and payload (de)serialization for an item
+///
+/// `path` is the path used to construct and destructure the item (i.e. `Enum::Variant` or `Struct`),
+/// and `attrs` are the attributes that contain the `mqtt_item` attribute describing the item.
+///
+/// `generics` are the generics of the implementation that the generated code is part of.
+/// If `borrowed` is set, the generated parse code is that of an `MqttItemRef` with that lifetime,
+/// which borrows from the topic and payload.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn generate_item_impl(
+    crate_name: &TokenStream,
+    container: &ContainerAttributes,
+    path: &TokenStream,
+    ident: &Ident,
+    attrs: &[Attribute],
+    fields: &Fields,
+    generics: &Generics,
+    span: Span,
+    borrowed: Option<&Lifetime>,
+) -> ItemImpl {
+    let attr = if let Some(attr) = get_mqtt_attribute(attrs) {
+        attr
+    } else {
+        abort!(span, "`{}` does not have an `mqtt_item` attribute", ident);
+    };
+
+    let attr_list = if let Some(list) = get_attribute_list(&attr) {
+        list
+    } else {
+        abort!(span, "The `mqtt_item` does not have a meta list");
+    };
+    let meta_list = &attr_list.nested;
+
+    let (fields, field_type): (Vec<IdentifiedField>, _) = match fields {
+        syn::Fields::Unnamed(fields) => (
+            fields
+                .unnamed
+                .iter()
+                .enumerate()
+                .map(|(idx, f)| IdentifiedField {
+                    ty: f.ty.clone(),
+                    ident: Ident::new(&format!("_mqttitem_field_field_{}", idx), span),
+                    name: format!("{}", idx),
+                    extend_into: field_flag_set(&f.attrs, "extend_into"),
+                    layer: field_flag_set(&f.attrs, "layer"),
+                    payload: field_flag_set(&f.attrs, "payload"),
+                })
+                .collect(),
+            FieldType::Unnamed,
+        ),
+        syn::Fields::Named(fields) => (
+            fields
+                .named
+                .iter()
+                .map(|f| IdentifiedField {
+                    ty: f.ty.clone(),
+                    ident: Ident::new(
+                        &format!("_mqttitem_field_{}", f.ident.clone().unwrap()),
+                        span,
+                    ),
+                    name: f.ident.clone().unwrap().to_string(),
+                    extend_into: field_flag_set(&f.attrs, "extend_into"),
+                    layer: field_flag_set(&f.attrs, "layer"),
+                    payload: field_flag_set(&f.attrs, "payload"),
+                })
+                .collect(),
+            FieldType::Named,
+        ),
+        syn::Fields::Unit => (Vec::new(), FieldType::Unit),
+    };
+
+    let mut not_processed_fields = fields.clone();
+    let mut process_field = |name: &String| {
+        let index = if let Some(idx) = not_processed_fields.iter().position(|f| &f.name == name) {
+            idx
+        } else {
+            abort!(
+                span,
+                format!("Field `{}` is specified more than once", name)
+            );
+        };
+        not_processed_fields.remove(index);
+    };
+
+    let mut topic = if let Some(topic) = get_kv(meta_list, "topic") {
+        let topic_lit = match topic {
+            syn::Lit::Str(str) => str,
+            _ => abort!(span, "The topic must be a literal string."),
+        };
+        Topic::from_string(
+            &topic_lit.value(),
+            &topic_lit.span(),
+            container.system_topic,
+        )
+    } else {
+        abort!(span, "Topic not defined");
+    };
+
+    // The nested field of this item: either the first field if the item itself is marked with
+    // `extend_into`, or the field marked with `extend_into` or `layer`
+    let mut nested = fields.iter().filter(|f| f.extend_into || f.layer);
+    let nested = match (nested.next(), nested.next()) {
+        (_, Some(_)) => abort!(
+            span,
+            "Only one field can be marked with `extend_into` or `layer`"
+        ),
+        (Some(field), None) => Some(field),
+        (None, None) if attribute_flag_set(&attr, "extend_into") => {
+            if let Some(field) = fields.first() {
+                Some(field)
+            } else {
+                abort!(span, "`extend_into` requires at least one field")
+            }
+        }
+        (None, None) => None,
+    };
+
+    match nested {
+        Some(field) if field.layer => {
+            let part = topic
+                .parts
+                .iter_mut()
+                .find(|part| matches!(part, TopicPart::Ident(ident) if ident == &field.name));
+            if let Some(part) = part {
+                *part = TopicPart::Nested(field.name.clone());
+            } else {
+                abort!(
+                    span,
+                    "Field `{}` is marked with `layer`, but `<{}>` is not part of the topic",
+                    field.name,
+                    field.name
+                );
+            }
+        }
+        Some(field) => topic.push(TopicPart::Nested(field.name.clone())),
+        None => {}
+    }
+
+    if topic.has_nested() && topic.has_rest() {
+        abort!(
+            span,
+            "Items with a nested `MqttItem` can not capture the remaining layers of the topic"
+        );
+    }
+
+    let payload_fields: Vec<String> = fields
+        .iter()
+        .filter(|f| f.payload)
+        .map(|f| f.name.clone())
+        .collect();
+
+    let payload = if let Some(payload) = get_kv(meta_list, "payload") {
+        if !payload_fields.is_empty() {
+            abort!(
+                span,
+                "The payload can not be defined by both the `payload` attribute and fields marked with `payload`"
+            );
+        }
+        match payload {
+            syn::Lit::Str(str) => Some(Payload::from_string(&str.value(), span)),
+            _ => abort!(span, "Payload field not found"),
+        }
+    } else if !payload_fields.is_empty() {
+        Some(Payload::Fields(payload_fields))
+    } else {
+        None
+    };
+    // Override_serialize
+    let override_serialization = if let Some(override_ser) = get_kv(meta_list, "serialize_using") {
+        match override_ser {
+            syn::Lit::Str(str) => {
+                let value: TokenStream = str.value().parse().unwrap();
+                Some(value)
+            }
+            _ => abort!(span, "Serialization function not found in derive"),
+        }
+    } else {
+        None
+    };
+
+    // Override_deserialize
+    let override_deserialization =
+        if let Some(override_deser) = get_kv(meta_list, "deserialize_using") {
+            match override_deser {
+                syn::Lit::Str(str) => {
+                    let value: TokenStream = str.value().parse().unwrap();
+                    Some(value)
+                }
+                _ => abort!(span, "Deserialization function not found in derive"),
+            }
+        } else {
+            None
+        };
+
+    let codec = get_codec(crate_name, Some(&attr_list));
+
+    // Topic mismatches are told apart from the errors of the item, which have its own error type
+    let deserialize_error_type = &container.deserialize_error_type;
+    let parse_error = quote! { #crate_name::ParseError::<#deserialize_error_type> };
+
+    // Settings on the item take precedence over the container-wide defaults, and a function
+    // takes precedence over a codec
+    let serialization = PayloadFunction::select(
+        override_serialization,
+        codec.clone(),
+        container.serialize_using.clone(),
+        container.codec.clone(),
+        crate_name,
+    );
+    let deserialization = PayloadFunction::select(
+        override_deserialization,
+        codec,
+        container.deserialize_using.clone(),
+        container.codec.clone(),
+        crate_name,
+    );
+
+    if payload.is_some() && topic.has_nested() {
+        abort!(
+            span,
+            "Items with a nested `MqttItem` can not have a payload, it is generated by the nested item"
+        );
+    }
+
+    let (payload_serialize, payload_write, payload_size_hint, payload_deserialize) = if let Some(
+        Payload::Single(payload),
+    ) = &payload
+    {
+        let IdentifiedField {
+            ty, ident, name, ..
+        } = find_field(field_type, span, &fields, payload);
+        process_field(name);
+
+        let write = serialization.write(crate_name, ty, quote! { &#ident });
+        let size_hint = serialization.size_hint(crate_name, ty, quote! { #ident });
+        let ser = |value: TokenStream| match &serialization {
+            PayloadFunction::Using(override_fun) => quote! {
+                #override_fun(payload, #value)?;
+            },
+            PayloadFunction::Codec(codec) => quote! {
+                <#codec as #crate_name::PayloadCodec<#ty>>::serialize(payload, #value)
+                    .map_err(Into::<#crate_name::MqttSerializeError>::into)?;
+            },
+        };
+        let ser = (ser(quote! { &#ident }), ser(quote! { #ident }));
+        let deser = match (deserialization, borrowed) {
+            (PayloadFunction::Using(override_fun), _) => quote! {
+                let #ident: #ty = #override_fun(payload).map_err(|e| #parse_error::Invalid(e.into()))?;
+            },
+            (PayloadFunction::Codec(codec), None) => quote! {
+                let #ident: #ty = <#codec as #crate_name::PayloadCodec<#ty>>::deserialize(payload)
+                    .map_err(|e| #parse_error::Invalid(Into::<#crate_name::MqttDeserializeError>::into(e).into()))?;
+            },
+            (PayloadFunction::Codec(codec), Some(lifetime)) => quote! {
+                let #ident: #ty = <#codec as #crate_name::PayloadCodecRef<#lifetime, #ty>>::deserialize_ref(payload)
+                    .map_err(|e| #parse_error::Invalid(Into::<#crate_name::MqttDeserializeError>::into(e).into()))?;
+            },
+        };
+
+        (Some(ser), Some(write), Some(size_hint), Some(deser))
+    } else if let Some(Payload::Fields(payload)) = &payload {
+        // The fields are (de)serialized through a struct containing all of them
+        let payload_fields: Vec<&IdentifiedField> = payload
+            .iter()
+            .map(|name| find_field(field_type, span, &fields, name))
+            .collect();
+        payload_fields
+            .iter()
+            .for_each(|field| process_field(&field.name));
+
+        let serde_crate = format!("{}::serde", crate_name);
+        let names: Vec<&String> = payload_fields.iter().map(|f| &f.name).collect();
+        let members: Vec<Ident> = payload_fields
+            .iter()
+            .map(|f| Ident::new(&format!("field_{}", f.name), span))
+            .collect();
+        let idents: Vec<&Ident> = payload_fields.iter().map(|f| &f.ident).collect();
+        let tys: Vec<&Type> = payload_fields.iter().map(|f| &f.ty).collect();
+
+        // The structs are defined in the generated functions, so they can't use the generics of
+        // the item. They get the same parameters instead, with a marker for those that they don't
+        // use. The bounds are left to serde, which would otherwise find them twice.
+        let generics = &unbounded_generics(generics);
+        let (payload_imp, payload_ty, _) = generics.split_for_impl();
+        let marker = generics_marker(generics);
+        let mut ref_generics = generics.clone();
+        ref_generics.params.insert(
+            0,
+            GenericParam::Lifetime(LifetimeDef::new(Lifetime::new(
+                "'__payload",
+                Span::call_site(),
+            ))),
+        );
+        let (ref_imp, ref_ty, _) = ref_generics.split_for_impl();
+        let mut de_generics = ref_generics.clone();
+        de_generics.params.insert(
+            0,
+            GenericParam::Lifetime(LifetimeDef::new(Lifetime::new("'de", Span::call_site()))),
+        );
+        let (de_imp, _, _) = de_generics.split_for_impl();
+
+        // The fields of a borrowed item can borrow from the payload, with the lifetime of the item
+        // that is part of `generics`. Codecs require owned payloads to be serializable too.
+        let payload_derive = if borrowed.is_some() {
+            quote! { #crate_name::serde::Deserialize }
+        } else {
+            quote! { #crate_name::serde::Serialize, #crate_name::serde::Deserialize }
+        };
+        let payload_struct = quote! {
+            #[derive(#payload_derive)]
+            #[serde(crate = #serde_crate)]
+            struct MqttItemPayload #payload_imp {
+                #(
+                    #[serde(rename = #names)]
+                    #members: #tys,
+                )*
+                #[serde(skip)]
+                __marker: #marker,
+            }
+        };
+        // The fields are serialized by reference, so that they don't need to be moved or cloned.
+        // Codecs require payloads to be deserializable, even though this one never is.
+        let payload_struct_serialize = quote! {
+            #[derive(#crate_name::serde::Serialize)]
+            #[serde(crate = #serde_crate)]
+            struct MqttItemPayloadRef #ref_imp {
+                #(
+                    #[serde(rename = #names)]
+                    #members: &'__payload #tys,
+                )*
+                #[serde(skip)]
+                __marker: #marker,
+            }
+            impl #de_imp #crate_name::serde::Deserialize<'de> for MqttItemPayloadRef #ref_ty {
+                fn deserialize<D>(_: D) -> Result<Self, D::Error>
+                where
+                    D: #crate_name::serde::Deserializer<'de>,
+                {
+                    Err(<D::Error as #crate_name::serde::de::Error>::custom(
+                        "payload references can not be deserialized",
+                    ))
+                }
+            }
+        };
+
+        let generic_args = generics.params.iter().map(|param| match param {
+            GenericParam::Lifetime(def) => def.lifetime.to_token_stream(),
+            GenericParam::Type(param) => param.ident.to_token_stream(),
+            GenericParam::Const(param) => param.ident.to_token_stream(),
+        });
+        let payload_ref_ty = quote! { MqttItemPayloadRef<'_, #(#generic_args),*> };
+        let write = serialization.write(crate_name, &payload_ref_ty, quote! { &payload_value });
+        let write = quote! {
+            #payload_struct_serialize
+            let payload_value = MqttItemPayloadRef {
+                #(#members: &#idents,)*
+                __marker: ::core::marker::PhantomData,
+            };
+            #write
+        };
+        let size_hint =
+            serialization.size_hint(crate_name, &payload_ref_ty, quote! { &payload_value });
+        let size_hint = quote! {
+            {
+                #payload_struct_serialize
+                let payload_value = MqttItemPayloadRef {
+                    #(#members: #idents,)*
+                    __marker: ::core::marker::PhantomData,
+                };
+                #size_hint
+            }
+        };
+        let ser = match serialization {
+            PayloadFunction::Using(override_fun) => quote! {
+                #override_fun(payload, &payload_value)?;
+            },
+            PayloadFunction::Codec(codec) => quote! {
+                <#codec as #crate_name::PayloadCodec<#payload_ref_ty>>::serialize(payload, &payload_value)
+                    .map_err(Into::<#crate_name::MqttSerializeError>::into)?;
+            },
+        };
+        let ser = (
+            quote! {
+                #payload_struct_serialize
+                let payload_value = MqttItemPayloadRef {
+                    #(#members: &#idents,)*
+                    __marker: ::core::marker::PhantomData,
+                };
+                #ser
+            },
+            quote! {
+                #payload_struct_serialize
+                let payload_value = MqttItemPayloadRef {
+                    #(#members: #idents,)*
+                    __marker: ::core::marker::PhantomData,
+                };
+                #ser
+            },
+        );
+
+        let deser = match (deserialization, borrowed) {
+            (PayloadFunction::Using(override_fun), _) => quote! {
+                #override_fun(payload).map_err(|e| #parse_error::Invalid(e.into()))?
+            },
+            (PayloadFunction::Codec(codec), None) => quote! {
+                <#codec as #crate_name::PayloadCodec<MqttItemPayload #payload_ty>>::deserialize(payload)
+                    .map_err(|e| #parse_error::Invalid(Into::<#crate_name::MqttDeserializeError>::into(e).into()))?
+            },
+            (PayloadFunction::Codec(codec), Some(lifetime)) => quote! {
+                <#codec as #crate_name::PayloadCodecRef<#lifetime, MqttItemPayload #payload_ty>>::deserialize_ref(payload)
+                    .map_err(|e| #parse_error::Invalid(Into::<#crate_name::MqttDeserializeError>::into(e).into()))?
+            },
+        };
+        let deser = quote! {
+            #payload_struct
+            let MqttItemPayload {
+                #(#members: #idents,)*
+                ..
+            } = #deser;
+        };
+
+        (Some(ser), Some(write), Some(size_hint), Some(deser))
+    } else {
+        (None, None, None, None)
+    };
+
+    let mut topic_push = Vec::new();
+    let mut topic_push_ref = Vec::new();
+    let mut topic_write = Vec::new();
+    let mut topic_parse = Vec::new();
+    let mut nested_size_hint = None;
+
+    for (idx, topic_layer) in topic.parts.iter().enumerate() {
+        let (push, push_ref, write) = match topic_layer {
+            TopicPart::Ident(ident) => {
+                let IdentifiedField { ident, name, .. } =
+                    find_field(field_type, span, &fields, ident);
+                process_field(name);
+
+                let push = |value: TokenStream| {
+                    quote! {
+                        topic
+                            .try_push(&#crate_name::__alloc::ToString::to_string(#value))
+                            .map_err(Into::<#crate_name::MqttSerializeError>::into)?;
+                    }
+                };
+                let write = quote! {
+                    #crate_name::TopicWriter::push_layer(topic, &#ident)?;
+                };
+                (push(quote! { &#ident }), push(quote! { #ident }), write)
+            }
+            TopicPart::Literal(literal) => {
+                let push = quote! {
+                    topic.push(#literal);
+                };
+                let write = quote! {
+                    #crate_name::TopicWriter::push_layer(topic, #literal)?;
+                };
+                (push.clone(), push, write)
+            }
+            TopicPart::Nested(ident) => {
+                let IdentifiedField { ident, name, .. } =
+                    find_field(field_type, span, &fields, ident);
+                process_field(name);
+
+                // Items that only implement `MqttItem` can still be nested in items that are
+                // serialized by value
+                let push = |push_nested: TokenStream| {
+                    quote! {
+                        let mut nested_topic = #crate_name::Topic::new();
+                        #push_nested?;
+                        if !nested_topic.str().is_empty() {
+                            topic.push(nested_topic.str());
+                        }
+                    }
+                };
+                let push_ref = push(quote! {
+                    #crate_name::ToTopicAndPayload::push_topic_and_payload_ref(#ident, &mut nested_topic, payload)
+                });
+                let push = push(quote! {
+                    #crate_name::MqttItem::push_topic_and_payload(#ident, &mut nested_topic, payload)
+                });
+                let write = quote! {
+                    #crate_name::MqttItem::write_topic_and_payload(#ident, topic, payload)?;
+                };
+                nested_size_hint = Some(quote! {
+                    #crate_name::MqttItem::payload_size_hint(#ident)
+                });
+                (push, push_ref, write)
+            }
+            TopicPart::Rest(ident) => {
+                let IdentifiedField { ident, name, .. } =
+                    find_field(field_type, span, &fields, ident);
+                process_field(name);
+
+                let push = |value: TokenStream| {
+                    quote! {
+                        #crate_name::TopicRest::push_layers(#value, topic)
+                            .map_err(Into::<#crate_name::MqttSerializeError>::into)?;
+                    }
+                };
+                let write = quote! {
+                    #crate_name::TopicRest::write_layers(&#ident, topic)?;
+                };
+                (push(quote! { &#ident }), push(quote! { #ident }), write)
+            }
+        };
+
+        topic_push.push(push);
+        topic_push_ref.push(push_ref);
+        topic_write.push(write);
+
+        let parse = match topic_layer {
+            TopicPart::Ident(ident) => {
+                let IdentifiedField {
+                    ty, ident, name, ..
+                } = find_field(field_type, span, &fields, ident);
+                if borrowed.is_some() && is_str_ref(ty) {
+                    quote! {
+                        let #ident = if let Some(value) = topic.next() {
+                            value
+                        } else {
+                            return Err(#parse_error::TopicMismatch(#crate_name::MqttDeserializeError::MissingTopicLayer(#name.into())));
+                        };
+                    }
+                } else {
+                    quote! {
+                        let #ident = if let Some(value) = topic.next() {
+                            value
+                                .parse()
+                                .map_err(|_| {
+                                    #parse_error::TopicMismatch(#crate_name::MqttDeserializeError::InvalidTopicLayer(#name.into(), value.into()))
+                                })?
+                        } else {
+                            return Err(#parse_error::TopicMismatch(#crate_name::MqttDeserializeError::MissingTopicLayer(#name.into())));
+                        };
+                    }
+                }
+            }
+            TopicPart::Literal(literal) => {
+                quote! {
+                    if let Some(value) = topic.next() {
+                        if value != #literal {
+                            return Err(#parse_error::TopicMismatch(#crate_name::MqttDeserializeError::UnexpectedTopicLayer(#literal.into(), value.into())));
+                        }
+                    } else {
+                        return Err(#parse_error::TopicMismatch(#crate_name::MqttDeserializeError::MissingTopicLayer(#literal.into())));
+                    }
+                }
+            }
+            TopicPart::Nested(ident) => {
+                let IdentifiedField {
+                    ty, ident, name, ..
+                } = find_field(field_type, span, &fields, ident);
+                // The nested item receives all layers, except for those that belong to the
+                // remaining parts of this topic
+                let suffix_len = topic.parts.len() - idx - 1;
+                let missing = quote! {
+                    return Err(#parse_error::TopicMismatch(#crate_name::MqttDeserializeError::MissingTopicLayer(#name.into())));
+                };
+                match borrowed {
+                    None => {
+                        let remaining = if suffix_len > 0 {
+                            Some(quote! {
+                                let mut topic = suffix.iter().copied();
+                            })
+                        } else {
+                            None
+                        };
+                        quote! {
+                            let layers: #crate_name::__alloc::Vec<&str> = topic.by_ref().collect();
+                            if layers.len() <= #suffix_len {
+                                #missing
+                            }
+                            let (nested, suffix) = layers.split_at(layers.len() - #suffix_len);
+                            let #ident: #ty = <#ty as #crate_name::MqttItem>::parse_topic_and_payload(
+                                #crate_name::Topic::from_str(&nested.join("/")),
+                                payload,
+                            )
+                            .map_err(#crate_name::ParseError::map_invalid::<#deserialize_error_type>)?;
+                            #remaining
+                        }
+                    }
+                    // The layers are split with clones of the iterator, so that borrowed items
+                    // don't need to collect them
+                    Some(lifetime) => quote! {
+                        let nested_len = match topic.clone().count().checked_sub(#suffix_len) {
+                            Some(len) if len > 0 => len,
+                            _ => {
+                                #missing
+                            }
+                        };
+                        let #ident: #ty = <#ty as #crate_name::MqttItemRef<#lifetime>>::parse_layers_and_payload(
+                            topic.clone().take(nested_len),
+                            payload,
+                        )
+                        .map_err(#crate_name::ParseError::map_invalid::<#deserialize_error_type>)?;
+                        let mut topic = topic.skip(nested_len);
+                    },
+                }
+            }
+            TopicPart::Rest(ident) => {
+                let IdentifiedField { ty, ident, .. } =
+                    find_field(field_type, span, &fields, ident);
+                quote! {
+                    let #ident = <#ty as #crate_name::TopicRest<'_>>::from_layers(&mut topic);
+                }
+            }
+        };
+
+        topic_parse.push(parse);
+    }
+
+    if !not_processed_fields.is_empty() {
+        let mut msg = "The following fields are not part of the topic or payload: ".to_string();
+        for field in not_processed_fields.iter() {
+            msg = format!("{}{}, ", msg, field.name)
+        }
+        abort!(span, msg);
+    }
+
+    let field_names: Vec<TokenStream> = fields
+        .iter()
+        .map(|f| {
+            let ident = f.ident.clone();
+            if field_type == FieldType::Unnamed {
+                quote! {
+                    #ident
+                }
+            } else {
+                let name = Ident::new(&f.name, span);
+                quote! {
+                    #name: #ident
+                }
+            }
+        })
+        .collect();
+
+    let pattern = match field_type {
+        FieldType::Named => quote! { #path { #(#field_names,)* } },
+        FieldType::Unnamed => quote! { #path ( #(#field_names,)* ) },
+        FieldType::Unit => quote! { #path },
+    };
+
+    // Unit items have no data to put in the payload, so they only accept an empty payload unless
+    // they are marked with `ignore_payload`
+    let ignore_payload = attribute_flag_set(&attr, "ignore_payload");
+    if ignore_payload && field_type != FieldType::Unit {
+        abort!(span, "`ignore_payload` is only supported on unit items");
+    }
+    let payload_check = if field_type == FieldType::Unit && !ignore_payload {
+        Some(quote! {
+            if !payload.is_empty() {
+                return Err(#parse_error::Invalid(
+                    #crate_name::MqttDeserializeError::InvalidPayload(
+                        #crate_name::__alloc::String::from_utf8_lossy(payload).into(),
+                    )
+                    .into(),
+                ));
+            }
+        })
+    } else {
+        None
+    };
+
+    let (payload_serialize, payload_serialize_ref) = payload_serialize.unzip();
+    let push = quote! {
+        #(#topic_push)*
+        #payload_serialize
+    };
+    let push_ref = quote! {
+        #(#topic_push_ref)*
+        #payload_serialize_ref
+    };
+
+    let write = quote! {
+        #(#topic_write)*
+        #payload_write
+    };
+
+    // Only one of the payload and the nested item exists
+    let size_hint = payload_size_hint
+        .or(nested_size_hint)
+        .unwrap_or_else(|| quote! { Some(0) });
+
+    let parse = quote! {
+        #(#topic_parse)*
+        if topic.next().is_some() {
+            return Err(#parse_error::TopicMismatch(#crate_name::MqttDeserializeError::UnknownLayer));
+        }
+        #payload_deserialize
+        #payload_check
+    };
+
+    ItemImpl {
+        pattern,
+        push,
+        push_ref,
+        write,
+        size_hint,
+        parse,
+        has_payload: payload.is_some(),
+        nested: nested.map(|field| field.ty.clone()),
+        topic,
+    }
+}
+
+/// Generate the body of `all_generic_topics` for the given items.
+///
+/// If any of the items contain a nested `MqttItem`, the generic topics are only known at runtime, so
+/// they are generated once and leaked.
+pub(crate) fn generate_generic_topics(
+    crate_name: &TokenStream,
+    items: &[(&Topic, Option<&Type>)],
+    generics: &Generics,
+    span: Span,
+) -> TokenStream {
+    if items.iter().all(|(_, nested)| nested.is_none()) {
+        let generic_topicstr: Vec<String> = items
+            .iter()
+            .map(|(topic, _)| topic.filter_string())
+            .collect();
+        return quote! {
+            &[#(#generic_topicstr ,)*]
+        };
+    }
+
+    if generics.type_params().next().is_some() {
+        abort!(
+            span,
+            "Nested `MqttItem` fields are not supported for items with generic type parameters"
+        );
+    }
+
+    let push_topics =
+        items.iter().map(
+            |(topic, nested)| match (nested, topic.nested_filter_strings()) {
+                (Some(ty), Some((prefix, suffix))) => quote! {
+                    for filter in <#ty as #crate_name::MqttItem>::all_generic_topics() {
+                        topics.push(#crate_name::leak_generic_topic(&[#prefix, filter, #suffix]));
+                    }
+                },
+                _ => {
+                    let filter = topic.filter_string();
+                    quote! {
+                        topics.push(#filter);
+                    }
+                }
+            },
+        );
+
+    quote! {
+        static TOPICS: #crate_name::GenericTopics = #crate_name::GenericTopics::new();
+        TOPICS.get_or_init(|| {
+            let mut topics = #crate_name::__alloc::Vec::new();
+            #(#push_topics)*
+            topics
+        })
+    }
+}
+
+/// The generics of the `ToTopicAndPayload` implementation of an item, with a where clause that
+/// requires the types of the `nested` items to implement it as well
+///
+/// Returns `None` if the item has nested items but is not marked with `by_ref`, in which case
+/// `ToTopicAndPayload` is not implemented, as the nested items may only implement `MqttItem`.
+pub(crate) fn to_topic_and_payload_generics<'b>(
+    crate_name: &TokenStream,
+    container: &ContainerAttributes,
+    generics: &Generics,
+    nested: impl Iterator<Item = &'b Type>,
+) -> Option<Generics> {
+    let mut generics = generics.clone();
+    let mut nested = nested.peekable();
+    if nested.peek().is_some() && !container.by_ref {
+        return None;
+    }
+    let where_clause = generics.make_where_clause();
+    for ty in nested {
+        where_clause.predicates.push(parse_quote! {
+            #ty: #crate_name::ToTopicAndPayload
+        });
+    }
+    Some(generics)
+}
+
+/// `generics` without their bounds, defaults and where clause
+fn unbounded_generics(generics: &Generics) -> Generics {
+    let mut generics = generics.clone();
+    generics.where_clause = None;
+    for param in generics.params.iter_mut() {
+        match param {
+            GenericParam::Lifetime(def) => {
+                def.colon_token = None;
+                def.bounds.clear();
+            }
+            GenericParam::Type(param) => {
+                param.colon_token = None;
+                param.bounds.clear();
+                param.eq_token = None;
+                param.default = None;
+            }
+            GenericParam::Const(param) => {
+                param.eq_token = None;
+                param.default = None;
+            }
+        }
+    }
+    generics
+}
+
+/// The type of a marker field that uses all lifetime and type parameters of `generics`
+fn generics_marker(generics: &Generics) -> TokenStream {
+    let lifetimes = generics.lifetimes().map(|def| &def.lifetime);
+    let types = generics.type_params().map(|param| &param.ident);
+    quote! {
+        ::core::marker::PhantomData<(#(&#lifetimes (),)* #(*const #types,)*)>
+    }
+}
+
+/// Check if the `mqtt_item` attribute in `attrs` contains `flag`
+fn field_flag_set(attrs: &[Attribute], flag: &str) -> bool {
+    get_mqtt_attribute(attrs)
+        .map(|attr| attribute_flag_set(&attr, flag))
+        .unwrap_or(false)
+}
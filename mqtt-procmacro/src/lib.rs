@@ -1,17 +1,42 @@
 #![allow(unused)]
-use enum_impl::impl_for_enum;
+use enum_impl::{impl_for_enum, impl_ref_for_enum};
 use proc_macro2::{Span, TokenStream};
 use proc_macro_crate::{crate_name, FoundCrate};
 use proc_macro_error::{abort, proc_macro_error};
+use quote::quote;
+use struct_impl::{impl_for_struct, impl_ref_for_struct};
 use syn::{
-    punctuated::Punctuated, spanned::Spanned, token::Comma, Attribute, Ident, Item, Lit, Meta,
-    MetaList, NestedMeta,
+    punctuated::Punctuated, spanned::Spanned, token::Comma, Attribute, GenericParam, Generics,
+    Ident, Item, Lifetime, LifetimeDef, Lit, Meta, MetaList, NestedMeta,
 };
 
 mod enum_impl;
+mod item_impl;
+mod struct_impl;
 
 pub(crate) type TokenVec = Vec<TokenStream>;
 
+/// The maximum length of a topic, in bytes, as defined by the MQTT specification
+const MAX_TOPIC_LEN: usize = 65535;
+
+/// Attributes of the derived struct or enum that apply to all of its items
+pub(crate) struct ContainerAttributes {
+    /// The `DeserializeError` of the generated `MqttItem` implementation
+    deserialize_error_type: TokenStream,
+    /// The `SerializeError` of the generated `MqttItem` implementation
+    serialize_error_type: TokenStream,
+    /// The default `serialize_using` function of all items with a payload
+    serialize_using: Option<TokenStream>,
+    /// The default `deserialize_using` function of all items with a payload
+    deserialize_using: Option<TokenStream>,
+    /// The default `PayloadCodec` of all items with a payload
+    codec: Option<TokenStream>,
+    /// Whether topics that start with `$` are intended, so that they are not rejected
+    system_topic: bool,
+    /// Whether `ToTopicAndPayload` is implemented for an item with nested items
+    by_ref: bool,
+}
+
 /// A part of a topic
 #[derive(Eq, Debug)]
 enum TopicPart {
@@ -19,6 +44,10 @@ enum TopicPart {
     Ident(String),
     /// A literal string
     Literal(String),
+    /// A nested `MqttItem`, which can span any amount of layers
+    Nested(String),
+    /// All remaining layers of the topic (#)
+    Rest(String),
 }
 
 impl PartialEq for TopicPart {
@@ -40,7 +69,13 @@ struct Topic {
 
 impl PartialEq for Topic {
     fn eq(&self, other: &Self) -> bool {
-        if self.parts.len() != other.parts.len() {
+        // The layers of a nested item are not known until runtime, so we can't detect collisions
+        if self.has_nested() || other.has_nested() {
+            false
+        } else if self.has_rest() != other.has_rest() {
+            // A topic with a rest layer is always attempted after those without one
+            false
+        } else if self.parts.len() != other.parts.len() {
             false
         } else {
             let zip = self.parts.iter().zip(other.parts.iter());
@@ -59,28 +94,79 @@ impl Topic {
         self.parts.push(value)
     }
 
-    fn from_string<T: Spanned>(input: &str, on: &T) -> Self {
+    /// Parse the topic `input` of an item, which may only start with `$` if `system_topic` is set
+    fn from_string(input: &str, span: &Span, system_topic: bool) -> Self {
         let mut me = Topic { parts: Vec::new() };
 
-        if input != "" {
-            let mut parts = input.split("/");
-
-            while let Some(part) = parts.next() {
-                if part.len() == 0 {
-                    abort!(on.span(), "Empty topic layers are not allowed");
+        if !input.is_empty() {
+            for part in input.split('/') {
+                if part.is_empty() {
+                    abort!(span, "Empty topic layers are not allowed");
                 }
-                if part.starts_with('<') && part.ends_with('>') {
+                if me.has_rest() {
+                    abort!(
+                        span,
+                        "`<{}..>` must be the last layer of the topic",
+                        me.rest_name()
+                    );
+                }
+                if part.starts_with('<') && part.ends_with("..>") {
+                    let value = &part[1..part.len() - 3];
+                    me.parts.push(TopicPart::Rest(value.to_string()))
+                } else if part.starts_with('<') && part.ends_with('>') {
                     let value = &part[1..part.len() - 1];
                     me.parts.push(TopicPart::Ident(value.to_string()))
                 } else {
+                    if let Some(c) = part.chars().find(|c| matches!(c, '+' | '#' | '\0')) {
+                        abort!(
+                            span,
+                            "Topic layer `{}` contains `{}`, which is not allowed in a topic",
+                            part.escape_debug(),
+                            c.escape_debug()
+                        );
+                    }
                     me.parts.push(TopicPart::Literal(part.to_string()))
                 }
             }
         }
 
+        let literal_len = me.literal_len();
+        if literal_len > MAX_TOPIC_LEN {
+            abort!(
+                span,
+                "Topic is at least {} bytes long, but topics can be at most {} bytes long",
+                literal_len,
+                MAX_TOPIC_LEN
+            );
+        }
+
+        if let Some(TopicPart::Literal(first)) = me.parts.first() {
+            if first.starts_with('$') && !system_topic {
+                abort!(
+                    span,
+                    "Topic `{}` starts with `$`, which is reserved for system topics of the broker. \
+                    Add `#[mqtt_item(system_topic)]` to the struct or enum if this is intended",
+                    input
+                );
+            }
+        }
+
         me
     }
 
+    /// The length, in bytes, of the literal layers of this topic and the separators between
+    /// all layers. This is the minimum length of any topic that this topic produces.
+    fn literal_len(&self) -> usize {
+        let literals: usize = self
+            .iter()
+            .map(|part| match part {
+                TopicPart::Literal(literal) => literal.len(),
+                _ => 0,
+            })
+            .sum();
+        literals + self.parts.len().saturating_sub(1)
+    }
+
     fn contains(&self, ident: &String) -> bool {
         for part in &self.parts {
             if let TopicPart::Ident(part) = part {
@@ -96,15 +182,48 @@ impl Topic {
         self.parts.iter()
     }
 
+    /// Whether this topic contains a nested item
+    fn has_nested(&self) -> bool {
+        self.iter().any(|part| matches!(part, TopicPart::Nested(_)))
+    }
+
+    /// Whether this topic ends with a layer that captures all remaining layers
+    fn has_rest(&self) -> bool {
+        matches!(self.parts.last(), Some(TopicPart::Rest(_)))
+    }
+
+    fn rest_name(&self) -> &str {
+        match self.parts.last() {
+            Some(TopicPart::Rest(name)) => name,
+            _ => "",
+        }
+    }
+
     fn filter_string(&self) -> String {
+        Self::parts_filter_string(&self.parts)
+    }
+
+    /// The filter strings of the layers before and after the nested item in this topic, if any
+    fn nested_filter_strings(&self) -> Option<(String, String)> {
+        let idx = self
+            .iter()
+            .position(|part| matches!(part, TopicPart::Nested(_)))?;
+        Some((
+            Self::parts_filter_string(&self.parts[..idx]),
+            Self::parts_filter_string(&self.parts[idx + 1..]),
+        ))
+    }
+
+    fn parts_filter_string(parts: &[TopicPart]) -> String {
         let mut filter = String::new();
-        for part in self.iter() {
+        for part in parts {
             match part {
                 TopicPart::Ident(_) => filter.push_str("+/"),
                 TopicPart::Literal(lit) => {
-                    filter.push_str(&lit);
-                    filter.push_str("/")
+                    filter.push_str(lit);
+                    filter.push('/')
                 }
+                TopicPart::Nested(_) | TopicPart::Rest(_) => filter.push_str("#/"),
             }
         }
 
@@ -116,42 +235,163 @@ impl Topic {
 /// A derive macro for producing MQTT topic + payloads, which implements the `MqttItem` trait
 /// for the derived item.
 ///
-/// It supports deriving on enums that only have variants with a single, unnamed, field. Additionally,
-/// one of the following must be true for the variant:
-/// * The field must implement [`serde::Serialize`] and [`serde::Deserialize`]
-/// * The variant is marked with `#[mqtt_item(extend_into)]` and the field implements `MqttItem`
+/// It supports deriving on enums and structs. Every enum variant, or the struct itself, must have
+/// an `#[mqtt_item(topic = "...")]` attribute describing its topic. Fields are placed in the topic
+/// using `<field_name>` (or `<0>` for tuple fields), and a field can be used as the payload using
+/// `payload = "<field_name>"`. Every field must be part of either the topic or the payload.
+///
+/// The derive also implements `ToTopicAndPayload`, which serializes the item by reference with
+/// `to_topic_and_payload` and `push_topic_and_payload_ref`. Items with a nested `MqttItem` field only implement it
+/// if they are marked with `by_ref`, which requires the type of that field to implement it as well.
+///
+/// Besides `push_topic_and_payload`, the generated implementation overrides `write_topic_and_payload`,
+/// which writes the topic layers and payload directly to `TopicWriter` and `PayloadWriter` buffers, such as
+/// `heapless::String<N>` or a `SliceWriter`, without allocating. Payloads are written with `PayloadCodec::write`,
+/// or through a temporary `Vec<u8>` if they are serialized with a `serialize_using` function.
+/// It also overrides `payload_size_hint`, which returns `PayloadCodec::size_hint` of the payload (or `None` for
+/// `serialize_using` functions).
 ///
 /// The `#[mqtt_item]` attribute is used for modifying the derive macro
 /// Currently supported struct/enum attributes:
-/// * `deserialize_error_type = "Type"`
-/// * `serialize_error_type = "Type"`
+/// * `deserialize_error_type = "Type"`. The `DeserializeError` of the generated implementation, which
+///    defaults to `MqttDeserializeError`.
+///    The type must implement `From<MqttDeserializeError>`, which is used to convert the errors
+///    produced while parsing the topic and payload, and `From` the error type of the used `deserialize_using`
+///    functions and nested items, if any. Enums are parsed as the first variant whose topic matches,
+///    so if the payload of that variant is invalid, its error is returned. If the topic of no variant matches,
+///    the error is `MqttDeserializeError::NoMatchingTopic`, which holds the reason for each variant.
+/// * `serialize_error_type = "Type"`. The `SerializeError` of the generated implementation, which defaults
+///    to `MqttSerializeError`. The type must implement `From<MqttSerializeError>` (and `From` the error type
+///    of the used `serialize_using` functions, if any).
+/// * `serialize_using = "path::to::function"`. The default `serialize_using` of all variants with a payload.
+/// * `deserialize_using = "path::to::function"`. The default `deserialize_using` of all variants with a payload.
+/// * `codec = "path::to::Codec"`. The default `PayloadCodec` of all variants with a payload.
+/// * `payload_format = "format"`. The default payload format of all variants with a payload.
+/// * `system_topic`. Allow topics that start with `$`, for items that are meant to use the
+///    system topics of the broker, such as `$aws/things/<thing>/shadow/update`.
+/// * `by_ref`. Implement `ToTopicAndPayload` for an item with nested `MqttItem` fields, whose types must
+///    implement `ToTopicAndPayload` as well. Items without nested fields always implement it.
+///
+/// Currently supported enum variant and struct attributes:
+/// * `topic = "layer/<field_name>"`. The last layer of the topic can be written as `<field_name..>`, which
+///    captures all remaining layers of the topic (the `#` wildcard) in the field. The field must
+///    implement `TopicRest`, such as `Vec<String>` or `Topic`. When serializing, fields in the topic may not
+///    contain `/`, `+`, `#` or NUL characters, as those would change the shape of the topic.
+///    Literal layers may not contain the `+` and `#` wildcards or NUL characters, and topics that start
+///    with `$` (which are reserved for the broker) are rejected, unless the item is marked with `system_topic`.
+/// * `payload = "<field_name>"`. The field is (de)serialized as the payload.
+/// * `payload = "{<field_name>, <other_field_name>}"`. The fields are (de)serialized together as the payload,
+///    as a map of field names to values. For example `{"temp":21.5,"humidity":40}`.
+/// * `ignore_payload`. Accept any payload when parsing a unit variant or unit struct, instead of only
+///    an empty payload.
+/// * `serialize_using = "path::to::function"`. Serialize the payload using
+///    `fn(&mut Vec<u8>, &T) -> Result<(), E>` instead of [`serde_json`]. Overrides the enum-wide default.
+/// * `deserialize_using = "path::to::function"`. Deserialize the payload using
+///    `fn(&[u8]) -> Result<T, E>` instead of [`serde_json`]. Overrides the enum-wide default.
+/// * `codec = "path::to::Codec"`. (De)serialize the payload using a type that implements `PayloadCodec`
+///    for the type of the payload, such as `JsonCodec` (the default), `RawCodec` or `TextCodec`.
+///    Overrides the enum-wide default, but `serialize_using` and `deserialize_using` take precedence.
+/// * `payload_format = "format"`. Shorthand for selecting one of the built-in codecs. Supported formats are:
+///   * `json`: `JsonCodec`
+///   * `text`: `TextCodec`, which uses the `Display` and `FromStr` implementations of the payload,
+///      without JSON quoting. For example `21.5` or `ON`.
+///   * `raw`: `RawCodec`, which copies a `Vec<u8>` (or `bytes::Bytes`, with the `bytes` feature of
+///      `mqtt_macro`) payload verbatim.
+///   * `cbor`: `CborCodec`, requires the `cbor` feature of `mqtt_macro`
+///   * `msgpack`: `MsgPackCodec`, which encodes structs as arrays. Requires the `msgpack` feature of `mqtt_macro`
+///   * `msgpack_named`: `MsgPackNamedCodec`, which encodes structs as maps. Requires the `msgpack` feature of `mqtt_macro`
+///
+/// Unit variants and unit structs are supported too. They serialize to an empty payload, and only
+/// accept an empty payload, unless they are marked with `ignore_payload`.
 ///
 /// Currently supported enum variant attributes:
 /// * `#[mqtt_item(extend_into)]`. This attribute causes the MQTT item's topic to be extended with that of the field. Generation
-///    of the payload of this `MqttItem` is delegated to the first field of this variant.
+///    of the payload of this `MqttItem` is delegated to the first field of this variant, which must implement `MqttItem`.
+///    The variant can not have a `payload`. For example `#[mqtt_item(topic = "site/<1>", extend_into)] Device(DeviceMsg, u32)`
 ///
-/// Currently supported struct field attributes:
-/// * `#[mqtt_item(layer])`. Requires all non-layer items to be part of the item's topic.
-///    This attribute causes the first layer of the field's topic to be placed at the identifier `<field_name>` in the topic
-///    of this `MqttItem`. Further generation of the topic and payload of this `MqttItem` is delegated to this field.
+/// Currently supported struct and enum variant field attributes:
+/// * `#[mqtt_item(layer)]`. Requires all non-layer items to be part of the item's topic.
+///    This attribute causes the topic of the field, which must implement `MqttItem`, to be
+///    placed at the identifier `<field_name>` in the topic of this `MqttItem`. The nested topic can span any amount
+///    of layers: when parsing, all layers that are not part of this item's topic are handed to the field.
+///    If a topic of the field captures the remaining layers (`<field_name..>`) and the field is not the last layer
+///    of the topic, the layers after it are left out of the generic topic, as a topic filter must end at `#`.
+///    That generic topic is broader than the topics of the item: a subscription to it also receives topics
+///    without those layers, which fail to parse as a topic mismatch.
 ///
 ///    Construction of this item's payload is delegated to the field marked with this attribute.
+///    Only one field per item can be marked with `layer` or `extend_into`. The `DeserializeError` of this item
+///    must implement `From` the `DeserializeError` of the field.
 /// * `#[mqtt_item(extend_into)]`. This behaves exactly as if the field is marked with `#[mqtt_item(layer)]`
 ///    and `<field_name>` is appended to the end of the topic of this `MqttItem`
+/// * `#[mqtt_item(payload)]`. The fields marked with this attribute are (de)serialized together as the payload,
+///    exactly as if they were listed in `payload = "{<field_name>, <other_field_name>}"`
 #[proc_macro_derive(MqttItem, attributes(mqtt_item))]
 #[proc_macro_error]
 pub fn mqtt_layer_ident(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let item: syn::Item = syn::parse(item).expect("Failed to parse input!");
+    let (crate_name, container) = container_attributes(&item);
 
-    let attr_list = match &item {
+    match &item {
+        Item::Enum(en) => impl_for_enum(&crate_name, &container, en).into(),
+        Item::Struct(st) => impl_for_struct(&crate_name, &container, st).into(),
+        _ => abort!(item, "Only enums and structs are supported."),
+    }
+}
+
+/// Derive `MqttItemRef`, which deserializes an item while borrowing from its topic and payload
+///
+/// The item is described by the same `#[mqtt_item]` attributes as for `MqttItem`. The item can have
+/// at most one lifetime parameter, which is the lifetime of the topic and payload that it borrows
+/// from. Fields in the topic that are of type `&str` borrow the topic layer, `<field_name..>` can be
+/// captured in a `Vec<&str>`, and the payload is deserialized with `PayloadCodecRef`, so that
+/// `JsonCodec` and the MessagePack codecs can deserialize any type that implements
+/// `serde::Deserialize<'a>`, and `RawCodec` can borrow the payload as `&[u8]` or `&str`.
+/// Nested items must implement `MqttItemRef` (and, when `MqttItem` is derived too, `MqttItem`).
+#[proc_macro_derive(MqttItemRef, attributes(mqtt_item))]
+#[proc_macro_error]
+pub fn mqtt_item_ref(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let item: syn::Item = syn::parse(item).expect("Failed to parse input!");
+    let (crate_name, container) = container_attributes(&item);
+
+    match &item {
+        Item::Enum(en) => impl_ref_for_enum(&crate_name, &container, en).into(),
+        Item::Struct(st) => impl_ref_for_struct(&crate_name, &container, st).into(),
+        _ => abort!(item, "Only enums and structs are supported."),
+    }
+}
+
+/// The lifetime of the topic and payload that an `MqttItemRef` with `generics` borrows from, and the
+/// generics of its implementation, which include that lifetime
+pub(crate) fn ref_generics(generics: &Generics, span: Span) -> (Lifetime, Generics) {
+    let mut lifetimes = generics.lifetimes();
+    match (lifetimes.next(), lifetimes.next()) {
+        (Some(def), None) => (def.lifetime.clone(), generics.clone()),
+        (None, None) => {
+            let lifetime = Lifetime::new("'__mqtt", Span::call_site());
+            let mut generics = generics.clone();
+            generics.params.insert(
+                0,
+                GenericParam::Lifetime(LifetimeDef::new(lifetime.clone())),
+            );
+            (lifetime, generics)
+        }
+        _ => abort!(
+            span,
+            "`MqttItemRef` can only be derived for items with at most one lifetime parameter"
+        ),
+    }
+}
+
+/// Get the crate name of `mqtt_macro` and the container attributes of `item`
+fn container_attributes(item: &Item) -> (TokenStream, ContainerAttributes) {
+    let attr_list = match item {
         Item::Enum(en) => Some(&en.attrs),
         Item::Struct(struct_def) => Some(&struct_def.attrs),
         _ => None,
     };
 
-    let attr_list = attr_list
-        .map(|list| list.iter().find_map(|attr| get_attribute_list(attr)))
-        .flatten();
+    let attr_list = attr_list.and_then(|list| list.iter().find_map(get_attribute_list));
 
     let crate_name = match crate_name("mqtt_macro") {
         Ok(FoundCrate::Itself) => "crate".to_string(),
@@ -168,31 +408,73 @@ pub fn mqtt_layer_ident(item: proc_macro::TokenStream) -> proc_macro::TokenStrea
     let serialize_error_type = get_kv_path_or_default(
         attr_list.as_ref(),
         "serialize_error_type",
-        "::serde_json::Error",
+        &format!("{}::MqttSerializeError", crate_name),
     );
 
     let crate_name = crate_name.parse().unwrap();
 
-    match &item {
-        Item::Enum(en) => impl_for_enum(&crate_name, en).into(),
-        _ => abort!(item, "Only enums are supported."),
-    }
+    let container = ContainerAttributes {
+        deserialize_error_type,
+        serialize_error_type,
+        serialize_using: get_kv_path(attr_list.as_ref(), "serialize_using"),
+        deserialize_using: get_kv_path(attr_list.as_ref(), "deserialize_using"),
+        codec: get_codec(&crate_name, attr_list.as_ref()),
+        system_topic: attr_list
+            .as_ref()
+            .map(|list| list_flag_set(list, "system_topic"))
+            .unwrap_or(false),
+        by_ref: attr_list
+            .as_ref()
+            .map(|list| list_flag_set(list, "by_ref"))
+            .unwrap_or(false),
+    };
+
+    (crate_name, container)
 }
 
-pub(crate) fn get_kv_path_or_default<'a>(
+pub(crate) fn get_kv_path_or_default(
     punctuated: Option<&MetaList>,
     key: &str,
     default: &str,
 ) -> TokenStream {
-    let lit = punctuated.map(|list| get_kv(&list.nested, key)).flatten();
+    get_kv_path(punctuated, key).unwrap_or_else(|| default.parse().unwrap())
+}
+
+/// Get a key-value from the attribute list, and parse its value as a path
+pub(crate) fn get_kv_path(punctuated: Option<&MetaList>, key: &str) -> Option<TokenStream> {
+    let lit = punctuated.and_then(|list| get_kv(&list.nested, key));
 
-    let ident = lit.map(|lit| match lit {
-        Lit::Str(lit_str) => lit_str.value(),
+    lit.map(|lit| match lit {
+        Lit::Str(lit_str) => lit_str.value().parse().unwrap(),
         _ => abort!(punctuated, "No support for literals other than Str yet."),
+    })
+}
+
+/// Get the `PayloadCodec` selected by either the `codec` or `payload_format` key in the attribute list
+pub(crate) fn get_codec(crate_name: &TokenStream, list: Option<&MetaList>) -> Option<TokenStream> {
+    let codec = get_kv_path(list, "codec");
+    let format = list.and_then(|list| get_kv(&list.nested, "payload_format"));
+
+    let format = format.map(|lit| match lit {
+        Lit::Str(lit_str) => match lit_str.value().as_str() {
+            "json" => quote! { #crate_name::JsonCodec },
+            "text" => quote! { #crate_name::TextCodec },
+            "raw" => quote! { #crate_name::RawCodec },
+            "cbor" => quote! { #crate_name::CborCodec },
+            "msgpack" => quote! { #crate_name::MsgPackCodec },
+            "msgpack_named" => quote! { #crate_name::MsgPackNamedCodec },
+            other => abort!(lit_str, "Unknown payload format `{}`", other),
+        },
+        _ => abort!(lit, "The payload format must be a literal string."),
     });
 
-    let path = ident.unwrap_or(default.to_string());
-    path.parse().unwrap()
+    match (codec, format) {
+        (Some(_), Some(_)) => abort!(
+            list,
+            "Only one of `codec` and `payload_format` can be specified"
+        ),
+        (codec, format) => codec.or(format),
+    }
 }
 
 /// Get a key-value from the nested list
@@ -201,18 +483,12 @@ pub(crate) fn get_kv<'a>(
     key: &str,
 ) -> Option<&'a Lit> {
     for value in punctuated.iter() {
-        match value {
-            NestedMeta::Meta(meta) => match meta {
-                Meta::NameValue(value) => {
-                    if let Some(ident) = value.path.get_ident() {
-                        if ident == key {
-                            return Some(&value.lit);
-                        }
-                    }
+        if let NestedMeta::Meta(Meta::NameValue(value)) = value {
+            if let Some(ident) = value.path.get_ident() {
+                if ident == key {
+                    return Some(&value.lit);
                 }
-                _ => {}
-            },
-            NestedMeta::Lit(_) => {}
+            }
         }
     }
     None
@@ -220,68 +496,75 @@ pub(crate) fn get_kv<'a>(
 
 /// Get the list contained in the `mqtt_item` attribute
 pub(crate) fn get_attribute_list(attribute: &Attribute) -> Option<MetaList> {
-    match attribute.parse_meta().unwrap() {
-        Meta::List(list) => {
-            if let Some(ident) = list.path.get_ident() {
-                if ident == "mqtt_item" {
-                    return Some(list);
-                }
+    if let Meta::List(list) = attribute.parse_meta().unwrap() {
+        if let Some(ident) = list.path.get_ident() {
+            if ident == "mqtt_item" {
+                return Some(list);
             }
         }
-        _ => {}
     }
     None
 }
 
 /// This checks if the `mqtt_item` attribute contains `flag`
 pub(crate) fn attribute_flag_set(attribute: &Attribute, flag: &str) -> bool {
-    get_attribute_list(attribute).map(|list| {
-        list.nested
-            .iter()
-            .find(|nested| match nested {
-                NestedMeta::Meta(meta) => match meta {
-                    Meta::Path(path) => path.segments.first().unwrap().ident == flag,
-                    _ => false,
-                },
-                NestedMeta::Lit(_) => false,
-            })
-            .is_some()
-    }) == Some(true)
+    get_attribute_list(attribute).map(|list| list_flag_set(&list, flag)) == Some(true)
 }
 
-pub(crate) fn get_mqtt_attribute(attributes: &Vec<Attribute>) -> Option<Attribute> {
-    attributes.clone().into_iter().find(|attr| {
-        attr.path.segments.first().map(|seg| seg.ident.to_string()) == Some("mqtt_item".to_string())
+/// This checks if the list of the `mqtt_item` attribute contains `flag`
+pub(crate) fn list_flag_set(list: &MetaList, flag: &str) -> bool {
+    list.nested.iter().any(|nested| match nested {
+        NestedMeta::Meta(Meta::Path(path)) => path.segments.first().unwrap().ident == flag,
+        _ => false,
     })
 }
 
+pub(crate) fn get_mqtt_attribute(attributes: &[Attribute]) -> Option<Attribute> {
+    attributes
+        .iter()
+        .find(|attr| {
+            attr.path.segments.first().map(|seg| seg.ident.to_string())
+                == Some("mqtt_item".to_string())
+        })
+        .cloned()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     #[test]
     fn topic_can_match_detection() {
-        let t1 = Topic::from_string("<hello>/world", &"");
-        let t2 = Topic::from_string("hello/<world>", &"");
+        let t1 = Topic::from_string("<hello>/world", &Span::call_site(), false);
+        let t2 = Topic::from_string("hello/<world>", &Span::call_site(), false);
         assert!(t1 == t2);
 
-        let t1 = Topic::from_string("hello/world", &"");
-        let t2 = Topic::from_string("hello/<world>", &"");
+        let t1 = Topic::from_string("hello/world", &Span::call_site(), false);
+        let t2 = Topic::from_string("hello/<world>", &Span::call_site(), false);
         assert!(t1 == t2);
 
-        let t1 = Topic::from_string("hello/world2", &"");
-        let t2 = Topic::from_string("hello/world", &"");
+        let t1 = Topic::from_string("hello/world2", &Span::call_site(), false);
+        let t2 = Topic::from_string("hello/world", &Span::call_site(), false);
         assert!(t1 != t2);
 
-        let t1 = Topic::from_string("hello/world2", &"");
-        let t2 = Topic::from_string("<hello>/world", &"");
+        let t1 = Topic::from_string("hello/world2", &Span::call_site(), false);
+        let t2 = Topic::from_string("<hello>/world", &Span::call_site(), false);
         assert!(t1 != t2);
 
-        let t1 = Topic::from_string("hello/world/again", &"");
-        let t2 = Topic::from_string("hello/<world>", &"");
+        let t1 = Topic::from_string("hello/world/again", &Span::call_site(), false);
+        let t2 = Topic::from_string("hello/<world>", &Span::call_site(), false);
         assert!(t1 != t2);
 
-        let t1 = Topic::from_string("hello/world/again", &"");
-        let t2 = Topic::from_string("hello/<world>/again", &"");
+        let t1 = Topic::from_string("hello/world/again", &Span::call_site(), false);
+        let t2 = Topic::from_string("hello/<world>/again", &Span::call_site(), false);
         assert!(t1 == t2);
     }
+
+    #[test]
+    fn topic_validation() {
+        let topic = Topic::from_string("hello/<world>/again", &Span::call_site(), false);
+        assert_eq!(topic.literal_len(), "hello//again".len());
+
+        let topic = Topic::from_string("$SYS/<broker>/uptime", &Span::call_site(), true);
+        assert_eq!(topic.literal_len(), "$SYS//uptime".len());
+    }
 }
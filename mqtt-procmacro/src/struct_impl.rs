@@ -0,0 +1,207 @@
+//! Implementation of struct derive macros for MqttItem and MqttItemRef
+
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{spanned::Spanned, ItemStruct};
+
+use crate::{
+    item_impl::{
+        generate_generic_topics, generate_item_impl, to_topic_and_payload_generics, ItemImpl,
+    },
+    ref_generics, ContainerAttributes,
+};
+
+pub fn impl_for_struct(
+    crate_name: &TokenStream,
+    container: &ContainerAttributes,
+    st: &ItemStruct,
+) -> TokenStream {
+    let st_ident = &st.ident;
+    let (imp, ty, wh) = st.generics.split_for_impl();
+    let ContainerAttributes {
+        deserialize_error_type,
+        serialize_error_type,
+        ..
+    } = container;
+
+    let path = quote! { #st_ident };
+
+    let ItemImpl {
+        pattern,
+        push,
+        push_ref,
+        write,
+        size_hint,
+        parse,
+        topic,
+        nested,
+        ..
+    } = generate_item_impl(
+        crate_name,
+        container,
+        &path,
+        st_ident,
+        &st.attrs,
+        &st.fields,
+        &st.generics,
+        st.span(),
+        None,
+    );
+
+    let generic_topics = generate_generic_topics(
+        crate_name,
+        &[(&topic, nested.as_ref())],
+        &st.generics,
+        st.span(),
+    );
+
+    let to_topic_and_payload =
+        to_topic_and_payload_generics(crate_name, container, &st.generics, nested.iter()).map(
+            |generics| {
+                let (_, _, ref_wh) = generics.split_for_impl();
+                quote! {
+                    impl #imp #crate_name::ToTopicAndPayload for #st_ident #ty #ref_wh {
+                        fn push_topic_and_payload_ref(&self, topic: &mut #crate_name::Topic, payload: &mut #crate_name::__alloc::Vec<u8>) -> Result<(), Self::SerializeError> {
+                            let #pattern = self;
+                            #push_ref
+                            Ok(())
+                        }
+                    }
+                }
+            },
+        );
+
+    quote! {
+
+        impl #imp #st_ident #ty #wh {
+            fn __mqttitem__parse<'__topic>(mut topic: impl Iterator<Item = &'__topic str>, payload: &[u8]) -> Result<Self, #crate_name::ParseError<#deserialize_error_type>> {
+                #parse
+                Ok(#pattern)
+            }
+        }
+
+        impl #imp #crate_name::MqttItem for #st_ident #ty #wh {
+            type DeserializeError = #deserialize_error_type;
+            type SerializeError = #serialize_error_type;
+
+            fn push_topic_and_payload(self, topic: &mut #crate_name::Topic, payload: &mut #crate_name::__alloc::Vec<u8>) -> Result<(), Self::SerializeError> {
+                let #pattern = self;
+                #push
+                Ok(())
+            }
+
+            fn write_topic_and_payload<__TopicWriter, __PayloadWriter>(
+                self,
+                topic: &mut __TopicWriter,
+                payload: &mut __PayloadWriter,
+            ) -> Result<(), Self::SerializeError>
+            where
+                __TopicWriter: #crate_name::TopicWriter + ?Sized,
+                __PayloadWriter: #crate_name::PayloadWriter + ?Sized,
+                Self::SerializeError: From<#crate_name::MqttSerializeError>,
+            {
+                let #pattern = self;
+                #crate_name::write_or_restore_topic(topic, |topic| -> Result<(), Self::SerializeError> {
+                    #write
+                    Ok(())
+                })
+            }
+
+            fn payload_size_hint(&self) -> Option<usize> {
+                #[allow(unused_variables)]
+                let #pattern = self;
+                #size_hint
+            }
+
+            fn from_topic_and_payload<'a>(
+                topic: #crate_name::Topic,
+                payload: &'a [u8],
+            ) -> Result<Self, Self::DeserializeError> {
+                Self::__mqttitem__parse(topic.layers(), payload).map_err(#crate_name::ParseError::into_error)
+            }
+
+            fn from_generic_topic_and_payload(
+                _index: usize,
+                topic: &#crate_name::Topic,
+                payload: &[u8],
+            ) -> Result<Self, Self::DeserializeError> {
+                Self::__mqttitem__parse(topic.layers(), payload).map_err(#crate_name::ParseError::into_error)
+            }
+
+            fn parse_topic_and_payload(
+                topic: #crate_name::Topic,
+                payload: &[u8],
+            ) -> Result<Self, #crate_name::ParseError<Self::DeserializeError>> {
+                Self::__mqttitem__parse(topic.layers(), payload)
+            }
+
+            fn all_generic_topics() -> &'static [&'static str] {
+                #generic_topics
+            }
+        }
+
+        #to_topic_and_payload
+
+    }
+}
+
+pub fn impl_ref_for_struct(
+    crate_name: &TokenStream,
+    container: &ContainerAttributes,
+    st: &ItemStruct,
+) -> TokenStream {
+    let st_ident = &st.ident;
+    let (lifetime, generics) = ref_generics(&st.generics, st.span());
+    let (imp, _, _) = generics.split_for_impl();
+    let (_, ty, wh) = st.generics.split_for_impl();
+    let deserialize_error_type = &container.deserialize_error_type;
+
+    let path = quote! { #st_ident };
+
+    let ItemImpl { pattern, parse, .. } = generate_item_impl(
+        crate_name,
+        container,
+        &path,
+        st_ident,
+        &st.attrs,
+        &st.fields,
+        &generics,
+        st.span(),
+        Some(&lifetime),
+    );
+
+    quote! {
+
+        impl #imp #st_ident #ty #wh {
+            fn __mqttitem__parse_ref(mut topic: impl Iterator<Item = &#lifetime str> + Clone, payload: &#lifetime [u8]) -> Result<Self, #crate_name::ParseError<#deserialize_error_type>> {
+                #parse
+                Ok(#pattern)
+            }
+        }
+
+        impl #imp #crate_name::MqttItemRef<#lifetime> for #st_ident #ty #wh {
+            type DeserializeError = #deserialize_error_type;
+
+            fn from_layers_and_payload<I>(
+                layers: I,
+                payload: &#lifetime [u8],
+            ) -> Result<Self, Self::DeserializeError>
+            where
+                I: Iterator<Item = &#lifetime str> + Clone,
+            {
+                Self::__mqttitem__parse_ref(layers, payload).map_err(#crate_name::ParseError::into_error)
+            }
+
+            fn parse_layers_and_payload<I>(
+                layers: I,
+                payload: &#lifetime [u8],
+            ) -> Result<Self, #crate_name::ParseError<Self::DeserializeError>>
+            where
+                I: Iterator<Item = &#lifetime str> + Clone,
+            {
+                Self::__mqttitem__parse_ref(layers, payload)
+            }
+        }
+
+    }
+}
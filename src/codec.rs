@@ -0,0 +1,407 @@
+use alloc::{string::ToString, vec::Vec};
+use core::{convert::Infallible, fmt::Display, str::FromStr};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+#[cfg(feature = "std")]
+use crate::IoWriter;
+#[cfg(feature = "std")]
+use crate::PayloadIo;
+use crate::{
+    serde_json_deserialize, serde_json_deserialize_ref, serde_json_serialize, FmtWriter,
+    MqttDeserializeError, MqttSerializeError, PayloadWriter,
+};
+
+/// A format that payloads of type `T` can be serialized to and deserialized from
+///
+/// A codec is selected for a payload with `#[mqtt_item(codec = "path::to::Codec")]`, either on
+/// the derived struct or enum, or on a single variant. If no codec is selected, [`JsonCodec`] is used.
+pub trait PayloadCodec<T> {
+    type SerializeError: Into<MqttSerializeError>;
+    type DeserializeError: Into<MqttDeserializeError>;
+
+    /// Serialize `value` and append it to `payload`
+    fn serialize(payload: &mut Vec<u8>, value: &T) -> Result<(), Self::SerializeError>;
+
+    /// Serialize `value` and write it to `writer`
+    ///
+    /// By default, `value` is serialized into a temporary `Vec` with [`PayloadCodec::serialize`],
+    /// which is then written to `writer`. Codecs that can write to `writer` directly override this.
+    fn write<W>(writer: &mut W, value: &T) -> Result<(), MqttSerializeError>
+    where
+        W: PayloadWriter + ?Sized,
+    {
+        let mut payload = Vec::new();
+        Self::serialize(&mut payload, value).map_err(Into::into)?;
+        writer.write_bytes(&payload)
+    }
+
+    /// Serialize `value` and write it to `writer`, such as a socket
+    #[cfg(feature = "std")]
+    fn serialize_into<W>(writer: &mut W, value: &T) -> Result<(), MqttSerializeError>
+    where
+        W: std::io::Write + ?Sized,
+    {
+        Self::write(&mut IoWriter::new(writer), value)
+    }
+
+    /// The length of `value` once serialized, if it is known without serializing `value`, which
+    /// can be used to preallocate buffers
+    ///
+    /// The default implementation returns `None`, codecs that know the length of `value` cheaply
+    /// override this.
+    fn size_hint(value: &T) -> Option<usize> {
+        let _ = value;
+        None
+    }
+
+    /// Deserialize a value from `payload`
+    fn deserialize(payload: &[u8]) -> Result<T, Self::DeserializeError>;
+}
+
+/// A [`PayloadCodec`] that can deserialize values of type `T` that borrow from the payload
+///
+/// This is used by items that derive [`MqttItemRef`](crate::MqttItemRef) to deserialize their
+/// payload without copying it.
+pub trait PayloadCodecRef<'a, T> {
+    type DeserializeError: Into<MqttDeserializeError>;
+
+    /// Deserialize a value from `payload`, which it can borrow from
+    fn deserialize_ref(payload: &'a [u8]) -> Result<T, Self::DeserializeError>;
+}
+
+/// A codec that encodes payloads as JSON, using `serde_json` (or `serde_json_core`, with the
+/// `serde-json-core` feature and without the `serde_json` feature)
+///
+/// With `std`, payloads are streamed to the [`PayloadWriter`]. Without it, `serde_json` can only
+/// serialize payloads into a temporary `String`, while `serde_json_core` writes them into the spare
+/// capacity of the writer (see [`PayloadWriter::write_in_place`]).
+pub struct JsonCodec;
+
+impl<T> PayloadCodec<T> for JsonCodec
+where
+    T: Serialize + DeserializeOwned,
+{
+    #[cfg(feature = "serde_json")]
+    type SerializeError = serde_json::Error;
+    #[cfg(all(feature = "serde-json-core", not(feature = "serde_json")))]
+    type SerializeError = serde_json_core::ser::Error;
+    type DeserializeError = MqttDeserializeError;
+
+    fn serialize(payload: &mut Vec<u8>, value: &T) -> Result<(), Self::SerializeError> {
+        serde_json_serialize(payload, value)
+    }
+
+    #[cfg(feature = "std")]
+    fn write<W>(writer: &mut W, value: &T) -> Result<(), MqttSerializeError>
+    where
+        W: PayloadWriter + ?Sized,
+    {
+        let mut io = PayloadIo::new(writer);
+        serde_json::to_writer(&mut io, value).map_err(|e| io.error(e))
+    }
+
+    #[cfg(all(feature = "serde-json-core", not(feature = "serde_json")))]
+    fn write<W>(writer: &mut W, value: &T) -> Result<(), MqttSerializeError>
+    where
+        W: PayloadWriter + ?Sized,
+    {
+        let result = writer.write_in_place(&mut |buffer| {
+            serde_json_core::to_slice(value, buffer).map_err(|e| match e {
+                serde_json_core::ser::Error::BufferFull => MqttSerializeError::CapacityExceeded,
+                e => MqttSerializeError::SerdeJsonCore(e),
+            })
+        });
+        match result {
+            Some(result) => result,
+            // The writer has no spare capacity to write to, such as a `PayloadWriter` that sends
+            // the payload elsewhere
+            None => {
+                let mut payload = Vec::new();
+                serde_json_serialize(&mut payload, value)?;
+                writer.write_bytes(&payload)
+            }
+        }
+    }
+
+    fn deserialize(payload: &[u8]) -> Result<T, Self::DeserializeError> {
+        serde_json_deserialize(payload)
+    }
+}
+
+impl<'a, T> PayloadCodecRef<'a, T> for JsonCodec
+where
+    T: Deserialize<'a>,
+{
+    type DeserializeError = MqttDeserializeError;
+
+    fn deserialize_ref(payload: &'a [u8]) -> Result<T, Self::DeserializeError> {
+        serde_json_deserialize_ref(payload)
+    }
+}
+
+/// A codec that copies the bytes of the payload verbatim
+///
+/// It is implemented for `Vec<u8>`, and for `bytes::Bytes` if the `bytes` feature is enabled.
+/// Items that derive [`MqttItemRef`](crate::MqttItemRef) can also borrow the payload as `&[u8]`,
+/// or as `&str` if it is valid UTF-8.
+pub struct RawCodec;
+
+impl PayloadCodec<Vec<u8>> for RawCodec {
+    type SerializeError = Infallible;
+    type DeserializeError = Infallible;
+
+    fn serialize(payload: &mut Vec<u8>, value: &Vec<u8>) -> Result<(), Self::SerializeError> {
+        payload.extend_from_slice(value);
+        Ok(())
+    }
+
+    fn write<W>(writer: &mut W, value: &Vec<u8>) -> Result<(), MqttSerializeError>
+    where
+        W: PayloadWriter + ?Sized,
+    {
+        writer.write_bytes(value)
+    }
+
+    fn size_hint(value: &Vec<u8>) -> Option<usize> {
+        Some(value.len())
+    }
+
+    fn deserialize(payload: &[u8]) -> Result<Vec<u8>, Self::DeserializeError> {
+        Ok(payload.to_vec())
+    }
+}
+
+impl<'a> PayloadCodecRef<'a, Vec<u8>> for RawCodec {
+    type DeserializeError = Infallible;
+
+    fn deserialize_ref(payload: &'a [u8]) -> Result<Vec<u8>, Self::DeserializeError> {
+        Ok(payload.to_vec())
+    }
+}
+
+impl<'a> PayloadCodecRef<'a, &'a [u8]> for RawCodec {
+    type DeserializeError = Infallible;
+
+    fn deserialize_ref(payload: &'a [u8]) -> Result<&'a [u8], Self::DeserializeError> {
+        Ok(payload)
+    }
+}
+
+impl<'a> PayloadCodecRef<'a, &'a str> for RawCodec {
+    type DeserializeError = MqttDeserializeError;
+
+    fn deserialize_ref(payload: &'a [u8]) -> Result<&'a str, Self::DeserializeError> {
+        core::str::from_utf8(payload).map_err(|_| MqttDeserializeError::NotUtf8)
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl PayloadCodec<bytes::Bytes> for RawCodec {
+    type SerializeError = Infallible;
+    type DeserializeError = Infallible;
+
+    fn serialize(payload: &mut Vec<u8>, value: &bytes::Bytes) -> Result<(), Self::SerializeError> {
+        payload.extend_from_slice(value);
+        Ok(())
+    }
+
+    fn write<W>(writer: &mut W, value: &bytes::Bytes) -> Result<(), MqttSerializeError>
+    where
+        W: PayloadWriter + ?Sized,
+    {
+        writer.write_bytes(value)
+    }
+
+    fn size_hint(value: &bytes::Bytes) -> Option<usize> {
+        Some(value.len())
+    }
+
+    fn deserialize(payload: &[u8]) -> Result<bytes::Bytes, Self::DeserializeError> {
+        Ok(bytes::Bytes::copy_from_slice(payload))
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl<'a> PayloadCodecRef<'a, bytes::Bytes> for RawCodec {
+    type DeserializeError = Infallible;
+
+    fn deserialize_ref(payload: &'a [u8]) -> Result<bytes::Bytes, Self::DeserializeError> {
+        Ok(bytes::Bytes::copy_from_slice(payload))
+    }
+}
+
+/// A codec that encodes payloads as UTF-8 text, using [`Display`] and [`FromStr`]
+///
+/// Unlike [`JsonCodec`], strings are not quoted: `String::from("ON")` is encoded as `ON`.
+pub struct TextCodec;
+
+impl<T> PayloadCodec<T> for TextCodec
+where
+    T: Display + FromStr,
+{
+    type SerializeError = Infallible;
+    type DeserializeError = MqttDeserializeError;
+
+    fn serialize(payload: &mut Vec<u8>, value: &T) -> Result<(), Self::SerializeError> {
+        payload.extend_from_slice(value.to_string().as_bytes());
+        Ok(())
+    }
+
+    fn write<W>(writer: &mut W, value: &T) -> Result<(), MqttSerializeError>
+    where
+        W: PayloadWriter + ?Sized,
+    {
+        FmtWriter::write_display(writer, value)
+    }
+
+    fn deserialize(payload: &[u8]) -> Result<T, Self::DeserializeError> {
+        let payload = core::str::from_utf8(payload).map_err(|_| MqttDeserializeError::NotUtf8)?;
+        payload
+            .parse()
+            .map_err(|_| MqttDeserializeError::InvalidPayload(payload.to_string()))
+    }
+}
+
+impl<'a, T> PayloadCodecRef<'a, T> for TextCodec
+where
+    T: FromStr,
+{
+    type DeserializeError = MqttDeserializeError;
+
+    fn deserialize_ref(payload: &'a [u8]) -> Result<T, Self::DeserializeError> {
+        let payload = core::str::from_utf8(payload).map_err(|_| MqttDeserializeError::NotUtf8)?;
+        payload
+            .parse()
+            .map_err(|_| MqttDeserializeError::InvalidPayload(payload.to_string()))
+    }
+}
+
+/// A codec that encodes payloads as CBOR, using [`ciborium`]
+#[cfg(feature = "cbor")]
+pub struct CborCodec;
+
+#[cfg(feature = "cbor")]
+impl<T> PayloadCodec<T> for CborCodec
+where
+    T: Serialize + DeserializeOwned,
+{
+    type SerializeError = ciborium::ser::Error<std::io::Error>;
+    type DeserializeError = MqttDeserializeError;
+
+    fn serialize(payload: &mut Vec<u8>, value: &T) -> Result<(), Self::SerializeError> {
+        crate::cbor_serialize(payload, value)
+    }
+
+    fn write<W>(writer: &mut W, value: &T) -> Result<(), MqttSerializeError>
+    where
+        W: PayloadWriter + ?Sized,
+    {
+        let mut io = PayloadIo::new(writer);
+        ciborium::into_writer(value, &mut io).map_err(|e| io.error(e))
+    }
+
+    fn deserialize(payload: &[u8]) -> Result<T, Self::DeserializeError> {
+        crate::cbor_deserialize(payload)
+    }
+}
+
+/// CBOR payloads are always deserialized into owned values, as [`ciborium`] can't borrow from them
+#[cfg(feature = "cbor")]
+impl<'a, T> PayloadCodecRef<'a, T> for CborCodec
+where
+    T: DeserializeOwned,
+{
+    type DeserializeError = MqttDeserializeError;
+
+    fn deserialize_ref(payload: &'a [u8]) -> Result<T, Self::DeserializeError> {
+        crate::cbor_deserialize(payload)
+    }
+}
+
+/// A codec that encodes payloads as MessagePack, using [`rmp_serde`]
+///
+/// Structs are encoded compactly, as arrays of their fields. Use [`MsgPackNamedCodec`] to
+/// encode them as maps instead.
+#[cfg(feature = "msgpack")]
+pub struct MsgPackCodec;
+
+#[cfg(feature = "msgpack")]
+impl<T> PayloadCodec<T> for MsgPackCodec
+where
+    T: Serialize + DeserializeOwned,
+{
+    type SerializeError = rmp_serde::encode::Error;
+    type DeserializeError = MqttDeserializeError;
+
+    fn serialize(payload: &mut Vec<u8>, value: &T) -> Result<(), Self::SerializeError> {
+        crate::msgpack_serialize(payload, value)
+    }
+
+    fn write<W>(writer: &mut W, value: &T) -> Result<(), MqttSerializeError>
+    where
+        W: PayloadWriter + ?Sized,
+    {
+        let mut io = PayloadIo::new(writer);
+        rmp_serde::encode::write(&mut io, value).map_err(|e| io.error(e))
+    }
+
+    fn deserialize(payload: &[u8]) -> Result<T, Self::DeserializeError> {
+        crate::msgpack_deserialize(payload)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+impl<'a, T> PayloadCodecRef<'a, T> for MsgPackCodec
+where
+    T: Deserialize<'a>,
+{
+    type DeserializeError = MqttDeserializeError;
+
+    fn deserialize_ref(payload: &'a [u8]) -> Result<T, Self::DeserializeError> {
+        rmp_serde::from_slice(payload).map_err(MqttDeserializeError::MsgPack)
+    }
+}
+
+/// A codec that encodes payloads as MessagePack, using [`rmp_serde`]
+///
+/// Structs are encoded as maps of their field names to their values.
+#[cfg(feature = "msgpack")]
+pub struct MsgPackNamedCodec;
+
+#[cfg(feature = "msgpack")]
+impl<T> PayloadCodec<T> for MsgPackNamedCodec
+where
+    T: Serialize + DeserializeOwned,
+{
+    type SerializeError = rmp_serde::encode::Error;
+    type DeserializeError = MqttDeserializeError;
+
+    fn serialize(payload: &mut Vec<u8>, value: &T) -> Result<(), Self::SerializeError> {
+        crate::msgpack_named_serialize(payload, value)
+    }
+
+    fn write<W>(writer: &mut W, value: &T) -> Result<(), MqttSerializeError>
+    where
+        W: PayloadWriter + ?Sized,
+    {
+        let mut io = PayloadIo::new(writer);
+        rmp_serde::encode::write_named(&mut io, value).map_err(|e| io.error(e))
+    }
+
+    fn deserialize(payload: &[u8]) -> Result<T, Self::DeserializeError> {
+        crate::msgpack_deserialize(payload)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+impl<'a, T> PayloadCodecRef<'a, T> for MsgPackNamedCodec
+where
+    T: Deserialize<'a>,
+{
+    type DeserializeError = MqttDeserializeError;
+
+    fn deserialize_ref(payload: &'a [u8]) -> Result<T, Self::DeserializeError> {
+        rmp_serde::from_slice(payload).map_err(MqttDeserializeError::MsgPack)
+    }
+}
@@ -1,9 +1,48 @@
 //! A crate for easily transforming structs and (simple) enums into MQTT items with a
 //! topic and payload.
+//!
+//! The crate is `no_std` if the default `std` feature is disabled, in which case it only depends
+//! on `alloc`. The `cbor` and `msgpack` codecs require `std`, so the `cbor` and `msgpack` features
+//! enable the `std` feature. JSON payloads are (de)serialized with `serde_json` if the
+//! `serde_json` feature is enabled, which `std` enables, and with `serde-json-core` otherwise. A
+//! `no_std` build must enable one of the `serde_json` and `serde-json-core` features.
+//!
+//! # `serde-json-core`
+//!
+//! With the `serde-json-core` feature and without the `serde_json` feature, JSON payloads are
+//! (de)serialized with `serde-json-core`, and `serde_json` is not a dependency. Its errors are
+//! reported as the `SerdeJsonCore` variants of [`MqttDeserializeError`] and
+//! [`MqttSerializeError`]. The feature has no effect if `serde_json` is enabled as well, so that a
+//! crate in the same dependency graph can't switch the JSON of `std` builds to the more limited
+//! backend.
+//!
+//! The JSON of `serde-json-core` differs slightly from that of `serde_json`, but both are valid
+//! JSON that parse to the same values: control characters are escaped with upper-case hex digits
+//! (`"\u001F"` instead of `"\u001f"`), and positive exponents are written without a `+` (`1e21`
+//! instead of `1e+21`).
+//!
+//! Unlike `serde_json`, `serde-json-core`:
+//! * panics when serializing a `char`, `i128` or `u128`
+//! * serializes maps with keys that are not strings to invalid JSON, such as `{1:2}`
+//! * panics when deserializing a non-empty map with keys that are not `&str`, such as
+//!   `BTreeMap<String, _>`
+//! * fails to deserialize `i128`, `u128`, surrogate pair escapes (such as `"\ud83d\ude00"`) and
+//!   self-describing types that use `deserialize_any`, such as `serde_json::Value`
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+#[cfg(not(any(feature = "serde_json", feature = "serde-json-core")))]
+compile_error!("either the `serde_json` or the `serde-json-core` feature must be enabled");
+
+use alloc::{boxed::Box, vec::Vec};
 
 mod serde_impl;
 
-// #[cfg(test)]
+mod codec;
+pub use codec::*;
+
+#[cfg(test)]
 mod tests;
 
 pub use serde_impl::*;
@@ -11,7 +50,26 @@ pub use serde_impl::*;
 mod topic;
 pub use topic::*;
 
-pub use mqtt_procmacro::MqttItem;
+mod router;
+pub use router::*;
+
+mod writer;
+pub use writer::*;
+
+pub use mqtt_procmacro::{MqttItem, MqttItemRef};
+
+// Used by the derive macro for payloads that consist of multiple fields
+#[doc(hidden)]
+pub use serde;
+
+// Used by the derive macro, so that the generated code does not depend on the prelude of `std`
+#[doc(hidden)]
+pub mod __alloc {
+    pub use alloc::{
+        string::{String, ToString},
+        vec::Vec,
+    };
+}
 
 pub trait MqttItem: Sized {
     type DeserializeError;
@@ -29,11 +87,44 @@ pub trait MqttItem: Sized {
     }
 
     /// Attempt to deserialize this [`MqttItem`] from the given topic and payload
+    #[allow(clippy::extra_unused_lifetimes)]
     fn from_topic_and_payload<'a>(
         topic: Topic,
         payload: &[u8],
     ) -> Result<Self, Self::DeserializeError>;
 
+    /// Attempt to deserialize this [`MqttItem`] from the given topic and payload, where `topic` is
+    /// known to match the generic topic at `index` of [`MqttItem::all_generic_topics`]
+    ///
+    /// Unlike [`MqttItem::from_topic_and_payload`], this does not attempt to parse `topic` as any of
+    /// the other generic topics. It is used by [`TopicRouter`] after it has matched the topic, which
+    /// it borrows so that it doesn't copy the topic for each item that it attempts. Derived items
+    /// parse the borrowed topic directly, while the default implementation clones it.
+    fn from_generic_topic_and_payload(
+        index: usize,
+        topic: &Topic,
+        payload: &[u8],
+    ) -> Result<Self, Self::DeserializeError> {
+        let _ = index;
+        Self::from_topic_and_payload(topic.clone(), payload)
+    }
+
+    /// Attempt to deserialize this [`MqttItem`], telling topics that don't belong to it apart from
+    /// other errors
+    ///
+    /// Derived items use it to deserialize their nested items, so that an enum that nests this item
+    /// tries its other variants if `topic` does not belong to this item, but returns the error of
+    /// this item if only its payload is invalid (see [`ParseError`]). Derived items implement it.
+    /// The default implementation considers every error of [`MqttItem::from_topic_and_payload`]
+    /// to be an invalid item, so hand-written items that can be nested in an enum should override
+    /// it.
+    fn parse_topic_and_payload(
+        topic: Topic,
+        payload: &[u8],
+    ) -> Result<Self, ParseError<Self::DeserializeError>> {
+        Self::from_topic_and_payload(topic, payload).map_err(ParseError::Invalid)
+    }
+
     /// Attempt to serialize this [`MqttItem`] and those serialized values
     /// into `topic` and `payload`
     fn push_topic_and_payload(
@@ -42,10 +133,200 @@ pub trait MqttItem: Sized {
         payload: &mut Vec<u8>,
     ) -> Result<(), Self::SerializeError>;
 
+    /// Attempt to serialize this [`MqttItem`] and write its topic and payload to the `topic` and
+    /// `payload` writers
+    ///
+    /// Unlike [`MqttItem::push_topic_and_payload`], the writers can have a fixed capacity, such as
+    /// `heapless::String<N>` and [`SliceWriter`]. If the topic or payload does not fit,
+    /// [`MqttSerializeError::CapacityExceeded`] is returned.
+    ///
+    /// On error, `topic` is restored to what it was before this call, but the part of the payload
+    /// that was written before the error is left in `payload`.
+    ///
+    /// Derived items write their topic and payload without allocating, unless the payload is
+    /// serialized with a `serialize_using` function or a codec that can not write to `payload`
+    /// directly (see [`PayloadCodec::write`]). The default implementation pushes the topic and
+    /// payload to a [`Topic`] and a `Vec<u8>` first.
+    ///
+    /// JSON payloads of `no_std` builds are serialized into a temporary `String` first, which
+    /// allocates. With the `serde-json-core` feature, they are written directly instead, if
+    /// `payload` has spare capacity to write them to (see [`PayloadWriter::write_in_place`]).
+    fn write_topic_and_payload<T, P>(
+        self,
+        topic: &mut T,
+        payload: &mut P,
+    ) -> Result<(), Self::SerializeError>
+    where
+        T: TopicWriter + ?Sized,
+        P: PayloadWriter + ?Sized,
+        Self::SerializeError: From<MqttSerializeError>,
+    {
+        let mut item_topic = Topic::new();
+        let mut item_payload = Vec::new();
+        self.push_topic_and_payload(&mut item_topic, &mut item_payload)?;
+
+        write_or_restore_topic(topic, |topic| {
+            if !item_topic.str().is_empty() {
+                for layer in item_topic.layers() {
+                    topic.push_layer(layer)?;
+                }
+            }
+            payload.write_bytes(&item_payload)?;
+            Ok(())
+        })
+    }
+
+    /// Attempt to serialize this [`MqttItem`], writing its payload to `writer` and returning its
+    /// topic
+    ///
+    /// Derived items stream their payload to `writer`, such as a socket or a reused buffer,
+    /// without serializing it into a `Vec<u8>` first (see [`MqttItem::write_topic_and_payload`]).
+    #[cfg(feature = "std")]
+    fn write_payload<W>(self, writer: &mut W) -> Result<Topic, Self::SerializeError>
+    where
+        W: std::io::Write + ?Sized,
+        Self::SerializeError: From<MqttSerializeError>,
+    {
+        let mut topic = Topic::new();
+        self.write_topic_and_payload(&mut topic, &mut IoWriter::new(writer))?;
+        Ok(topic)
+    }
+
+    /// The length of the payload of this [`MqttItem`] once serialized, which publishers can use
+    /// to preallocate their buffers
+    ///
+    /// Returns `None` if the length is not known without serializing the payload. Derived items
+    /// return [`PayloadCodec::size_hint`] of their payload, which only [`RawCodec`] knows of the
+    /// built-in codecs, `None` for payloads that are serialized with a `serialize_using` function,
+    /// and `Some(0)` if they have no payload. The default implementation returns `None`.
+    fn payload_size_hint(&self) -> Option<usize> {
+        None
+    }
+
     /// Generates a list of all generic topics
+    ///
+    /// The generic topics are topic filters that match all topics of the item. A filter can be
+    /// broader than the topics that the item can parse: if a nested item captures the remaining
+    /// layers of the topic but is followed by more layers, the filter ends at the `#` of the nested
+    /// item, as MQTT does not allow layers after `#`.
     fn all_generic_topics() -> &'static [&'static str];
 }
 
+/// An [`MqttItem`] that can be serialized by reference, without consuming it
+///
+/// It is implemented by the [`MqttItem`](derive@MqttItem) derive macro. Derived items with a
+/// nested [`MqttItem`] field only implement it if they are marked with `#[mqtt_item(by_ref)]`,
+/// which requires the type of that field to implement it as well, but can always be serialized by
+/// value.
+pub trait ToTopicAndPayload: MqttItem {
+    /// Attempt to serialize this [`MqttItem`] into it's corresponding [`Topic`] and a byte
+    /// payload, without consuming it
+    fn to_topic_and_payload(&self) -> Result<(Topic, Vec<u8>), Self::SerializeError> {
+        let mut topic = Topic::new();
+        let mut payload = Vec::new();
+
+        self.push_topic_and_payload_ref(&mut topic, &mut payload)?;
+
+        Ok((topic, payload))
+    }
+
+    /// Attempt to serialize this [`MqttItem`] and those serialized values into `topic` and
+    /// `payload`, without consuming it
+    fn push_topic_and_payload_ref(
+        &self,
+        topic: &mut Topic,
+        payload: &mut Vec<u8>,
+    ) -> Result<(), Self::SerializeError>;
+}
+
+/// An item that can be deserialized from a topic and payload that it borrows from
+///
+/// Unlike [`MqttItem`], the fields of an [`MqttItemRef`] can borrow topic layers (as `&'a str`)
+/// and payloads (as any type that implements `serde::Deserialize<'a>`), so that no allocations
+/// are needed to deserialize it.
+pub trait MqttItemRef<'a>: Sized {
+    type DeserializeError;
+
+    /// Attempt to deserialize this [`MqttItemRef`] from the given topic and payload
+    fn from_topic_and_payload_ref(
+        topic: &'a Topic,
+        payload: &'a [u8],
+    ) -> Result<Self, Self::DeserializeError> {
+        Self::from_layers_and_payload(topic.layers(), payload)
+    }
+
+    /// Attempt to deserialize this [`MqttItemRef`] from the given topic layers and payload
+    fn from_layers_and_payload<I>(
+        layers: I,
+        payload: &'a [u8],
+    ) -> Result<Self, Self::DeserializeError>
+    where
+        I: Iterator<Item = &'a str> + Clone;
+
+    /// Attempt to deserialize this [`MqttItemRef`], telling topics that don't belong to it apart
+    /// from other errors
+    ///
+    /// Like [`MqttItem::parse_topic_and_payload`], derived items use it to deserialize their
+    /// nested items, and implement it. The default implementation considers every error of
+    /// [`MqttItemRef::from_layers_and_payload`] to be an invalid item, so hand-written items that
+    /// can be nested in an enum should override it.
+    fn parse_layers_and_payload<I>(
+        layers: I,
+        payload: &'a [u8],
+    ) -> Result<Self, ParseError<Self::DeserializeError>>
+    where
+        I: Iterator<Item = &'a str> + Clone,
+    {
+        Self::from_layers_and_payload(layers, payload).map_err(ParseError::Invalid)
+    }
+}
+
+/// Join the non-empty topic filter `layers` and leak the result, so that the generic
+/// topics of nested items can be returned from [`MqttItem::all_generic_topics`]
+///
+/// If a layer follows a layer that ends with the multi-level wildcard `#`, which happens when a
+/// nested item whose topic captures the remaining layers is not placed at the end of the topic,
+/// the filter is truncated after the `#`. The result is a broader filter, which still matches all
+/// topics of the item: parsing the topic checks the layers after the `#`.
+#[doc(hidden)]
+pub fn leak_generic_topic(layers: &[&str]) -> &'static str {
+    let filter: Vec<&str> = layers
+        .iter()
+        .copied()
+        .filter(|layer| !layer.is_empty())
+        .collect();
+    let mut filter = filter.join("/");
+    // Literal layers can't contain `#`, so this is the `#` wildcard
+    if let Some(idx) = filter.find("#/") {
+        filter.truncate(idx + 1);
+    }
+    Box::leak(filter.into_boxed_str())
+}
+
+/// The generic topics of an item with nested items, which are generated once and leaked
+#[doc(hidden)]
+pub struct GenericTopics {
+    topics: once_cell::race::OnceBox<Vec<&'static str>>,
+}
+
+impl GenericTopics {
+    #[allow(clippy::new_without_default)]
+    pub const fn new() -> Self {
+        Self {
+            topics: once_cell::race::OnceBox::new(),
+        }
+    }
+
+    /// Get the generic topics, generating them with `init` if they weren't yet. If this is called
+    /// concurrently, `init` may run more than once, but only one of the results is kept
+    pub fn get_or_init(
+        &'static self,
+        init: impl FnOnce() -> Vec<&'static str>,
+    ) -> &'static [&'static str] {
+        self.topics.get_or_init(|| Box::new(init()))
+    }
+}
+
 #[macro_export]
 macro_rules! parse_or_err {
     ($topic: expr, $layer_id: literal) => {
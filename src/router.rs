@@ -0,0 +1,169 @@
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use crate::{MqttDeserializeError, MqttItem, Topic};
+
+/// Deserializes a topic and payload that match the generic topic of a route
+type Parse<T, E> = Box<dyn Fn(&Topic, &[u8]) -> Result<T, E> + Send + Sync>;
+
+struct Route<T, E> {
+    filter: &'static str,
+    parse: Parse<T, E>,
+}
+
+/// A single layer in the trie of a [`TopicRouter`]
+#[derive(Default)]
+struct Node {
+    /// The children of this node for literal layers
+    literals: BTreeMap<String, Node>,
+    /// The child of this node for the `+` wildcard
+    single: Option<Box<Node>>,
+    /// The routes of the filters that end in `#` after this node
+    multi: Vec<usize>,
+    /// The routes of the filters that end at this node
+    routes: Vec<usize>,
+}
+
+impl Node {
+    /// Visit the routes of all filters that match the remaining `layers`, until `visit` returns `true`
+    ///
+    /// Routes are visited from most to least specific: literal layers are visited before `+`, which
+    /// is visited before `#`. Returns whether `visit` returned `true`.
+    fn visit<'a, I, F>(&self, mut layers: I, wildcards: bool, visit: &mut F) -> bool
+    where
+        I: Iterator<Item = &'a str> + Clone,
+        F: FnMut(usize) -> bool,
+    {
+        let layer = match layers.next() {
+            Some(layer) => layer,
+            // `#` also matches the parent layer of the filter
+            None => return self.routes.iter().chain(&self.multi).any(|r| visit(*r)),
+        };
+
+        if let Some(child) = self.literals.get(layer) {
+            if child.visit(layers.clone(), true, visit) {
+                return true;
+            }
+        }
+
+        if !wildcards {
+            return false;
+        }
+
+        if let Some(child) = &self.single {
+            if child.visit(layers, true, visit) {
+                return true;
+            }
+        }
+
+        self.multi.iter().any(|r| visit(*r))
+    }
+}
+
+/// Dispatches incoming messages to one of several [`MqttItem`] types
+///
+/// The generic topics of all added items are stored in a trie, so that the items whose generic
+/// topic matches an incoming topic are found in a single walk over the layers of that topic.
+/// Items are then deserialized with [`MqttItem::from_generic_topic_and_payload`], and converted into
+/// a common type `T`.
+pub struct TopicRouter<T, E = MqttDeserializeError> {
+    root: Node,
+    routes: Vec<Route<T, E>>,
+}
+
+impl<T, E> TopicRouter<T, E> {
+    /// Create a new router without any items
+    pub fn new() -> Self {
+        Self {
+            root: Node::default(),
+            routes: Vec::new(),
+        }
+    }
+
+    /// Add the generic topics of `M` to this router
+    ///
+    /// Items of type `M` that are dispatched by this router are converted into `T` using `map`.
+    pub fn add<M>(&mut self, map: fn(M) -> T) -> &mut Self
+    where
+        M: MqttItem + 'static,
+        M::DeserializeError: Into<E>,
+        T: 'static,
+        E: 'static,
+    {
+        for (index, filter) in M::all_generic_topics().iter().enumerate() {
+            let parse: Parse<T, E> = Box::new(move |topic, payload| {
+                M::from_generic_topic_and_payload(index, topic, payload)
+                    .map(map)
+                    .map_err(Into::into)
+            });
+            self.insert(filter, parse);
+        }
+        self
+    }
+
+    fn insert(&mut self, filter: &'static str, parse: Parse<T, E>) {
+        let route = self.routes.len();
+        self.routes.push(Route { filter, parse });
+
+        let mut node = &mut self.root;
+        for layer in filter.split('/') {
+            node = match layer {
+                "#" => {
+                    node.multi.push(route);
+                    return;
+                }
+                "+" => node.single.get_or_insert_with(Default::default),
+                literal => node.literals.entry(literal.to_string()).or_default(),
+            };
+        }
+        node.routes.push(route);
+    }
+
+    /// All generic topics of the items in this router, which can be used to subscribe to them
+    pub fn filters(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.routes.iter().map(|route| route.filter)
+    }
+
+    /// Deserialize `topic` and `payload` as the item whose generic topic matches `topic`
+    ///
+    /// If the generic topics of several items match, the most specific one that can be deserialized
+    /// is returned, or the error of the most specific one if none of them can be deserialized.
+    /// Returns `None` if no generic topic matches `topic`.
+    pub fn dispatch(&self, topic: &Topic, payload: &[u8]) -> Option<Result<T, E>> {
+        let mut item = None;
+        let mut error = None;
+
+        // Wildcards in the first layer of a filter don't match topics that start with `$`
+        let wildcards = !topic.str().starts_with('$');
+        self.root.visit(
+            topic.layers(),
+            wildcards,
+            &mut |route| match (self.routes[route].parse)(topic, payload) {
+                Ok(value) => {
+                    item = Some(value);
+                    true
+                }
+                Err(e) => {
+                    error.get_or_insert(e);
+                    false
+                }
+            },
+        );
+
+        match (item, error) {
+            (Some(item), _) => Some(Ok(item)),
+            (None, Some(error)) => Some(Err(error)),
+            (None, None) => None,
+        }
+    }
+}
+
+impl<T, E> Default for TopicRouter<T, E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
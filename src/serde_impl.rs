@@ -1,29 +1,221 @@
-use serde::{de::DeserializeOwned, Serialize};
+use alloc::{string::String, vec::Vec};
+use core::{convert::Infallible, fmt::Display};
 
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::TopicError;
+
+/// Serialize `value` as JSON and append it to `payload`
+#[cfg(feature = "serde_json")]
 pub fn serde_json_serialize<T>(payload: &mut Vec<u8>, value: &T) -> Result<(), serde_json::Error>
 where
     T: Serialize,
 {
-    let string = serde_json::to_string(value)?;
-    let bytes = string.as_bytes();
-    bytes.iter().for_each(|val| payload.push(*val));
-    Ok(())
+    #[cfg(feature = "std")]
+    {
+        serde_json::to_writer(payload, value)
+    }
+
+    #[cfg(not(feature = "std"))]
+    {
+        let string = serde_json::to_string(value)?;
+        payload.extend_from_slice(string.as_bytes());
+        Ok(())
+    }
 }
 
+/// Serialize `value` as JSON with `serde_json_core` and append it to `payload`
+///
+/// This replaces the `serde_json` version if the `serde_json` feature is disabled.
+/// `serde_json_core` does not support all types, see the
+/// [crate documentation](crate#serde-json-core).
+#[cfg(all(feature = "serde-json-core", not(feature = "serde_json")))]
+pub fn serde_json_serialize<T>(
+    payload: &mut Vec<u8>,
+    value: &T,
+) -> Result<(), serde_json_core::ser::Error>
+where
+    T: Serialize,
+{
+    // The length of the JSON is not known up front, so the space that it is serialized into is
+    // doubled until it fits
+    let start = payload.len();
+    let mut len = 64;
+    loop {
+        payload.resize(start + len, 0);
+        match serde_json_core::to_slice(value, &mut payload[start..]) {
+            Ok(written) => {
+                payload.truncate(start + written);
+                return Ok(());
+            }
+            Err(serde_json_core::ser::Error::BufferFull) => len *= 2,
+            Err(e) => {
+                payload.truncate(start);
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// An error that occurred while deserializing an [`MqttItem`](crate::MqttItem)
 #[derive(Debug)]
 pub enum MqttDeserializeError {
+    /// The topic has no layer for the given field or literal
     MissingTopicLayer(String),
+    /// The topic has more layers than expected
     UnknownLayer,
+    /// The payload is not valid UTF-8
     NotUtf8,
+    /// The topic or payload is invalid, without a more specific reason. This is produced when a
+    /// `deserialize_using` function returns `Err(())`, and by
+    /// [`MqttItem::from_generic_topic_and_payload`](crate::MqttItem::from_generic_topic_and_payload)
+    /// of derived enums for an index that is not one of their generic topics
     Invalid,
+    /// The topic layer for the given field (the first value) could not be parsed from the given
+    /// layer (the second value)
     InvalidTopicLayer(String, String),
+    /// The topic layer that was expected to be the given literal (the first value) is the given
+    /// layer (the second value)
+    UnexpectedTopicLayer(String, String),
+    /// The payload could not be parsed as its target type
+    InvalidPayload(String),
+    /// The payload could not be deserialized as JSON
+    #[cfg(feature = "serde_json")]
     Serde(serde_json::Error),
+    /// The payload could not be deserialized as JSON by `serde_json_core`
+    #[cfg(feature = "serde-json-core")]
+    SerdeJsonCore(serde_json_core::de::Error),
+    #[cfg(feature = "cbor")]
+    Cbor(ciborium::de::Error<std::io::Error>),
+    #[cfg(feature = "msgpack")]
+    MsgPack(rmp_serde::decode::Error),
+    /// The topic matches none of the variants of an enum, for the given reasons
+    NoMatchingTopic(Vec<VariantError>),
+}
+
+impl MqttDeserializeError {
+    /// Whether this error means that the topic does not belong to the item, as opposed to an
+    /// invalid payload of an item whose topic matches
+    pub fn is_topic_mismatch(&self) -> bool {
+        matches!(
+            self,
+            Self::MissingTopicLayer(_)
+                | Self::UnknownLayer
+                | Self::InvalidTopicLayer(_, _)
+                | Self::UnexpectedTopicLayer(_, _)
+                | Self::NoMatchingTopic(_)
+        )
+    }
+}
+
+/// The error of [`MqttItem::parse_topic_and_payload`](crate::MqttItem::parse_topic_and_payload),
+/// which tells topics that don't belong to an item apart from other errors, so that the errors of
+/// the item can keep their own type `E`
+#[derive(Debug)]
+pub enum ParseError<E> {
+    /// The topic does not belong to the item, for the given reason
+    TopicMismatch(MqttDeserializeError),
+    /// The topic belongs to the item, but it could not be deserialized. This is produced when a
+    /// field's parser, such as a `deserialize_using` function or a nested item, returns its own
+    /// `DeserializeError`, which is kept as is instead of being converted to
+    /// [`MqttDeserializeError`]
+    Invalid(E),
+}
+
+impl<E> ParseError<E> {
+    /// Convert this error into the `DeserializeError` of the item
+    pub fn into_error(self) -> E
+    where
+        E: From<MqttDeserializeError>,
+    {
+        match self {
+            Self::TopicMismatch(error) => error.into(),
+            Self::Invalid(error) => error,
+        }
+    }
+
+    /// Convert the error of a nested item into that of the item that contains it
+    pub fn map_invalid<F>(self) -> ParseError<F>
+    where
+        F: From<E>,
+    {
+        match self {
+            Self::TopicMismatch(error) => ParseError::TopicMismatch(error),
+            Self::Invalid(error) => ParseError::Invalid(error.into()),
+        }
+    }
+}
+
+/// The reason that the topic does not match a single variant of an enum
+#[derive(Debug, PartialEq)]
+pub struct VariantError {
+    /// The name of the variant
+    pub variant: &'static str,
+    /// Why the variant could not be deserialized
+    pub error: MqttDeserializeError,
+}
+
+impl Display for MqttDeserializeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::MissingTopicLayer(layer) => write!(f, "topic is missing layer `{}`", layer),
+            Self::UnknownLayer => write!(f, "topic has more layers than expected"),
+            Self::NotUtf8 => write!(f, "payload is not valid UTF-8"),
+            Self::Invalid => write!(f, "topic or payload is invalid"),
+            Self::InvalidTopicLayer(field, layer) => {
+                write!(f, "topic layer `{}` is not a valid `{}`", layer, field)
+            }
+            Self::UnexpectedTopicLayer(expected, layer) => {
+                write!(f, "expected topic layer `{}`, found `{}`", expected, layer)
+            }
+            Self::InvalidPayload(payload) => write!(f, "payload `{}` is invalid", payload),
+            #[cfg(feature = "serde_json")]
+            Self::Serde(e) => write!(f, "payload is not valid JSON: {}", e),
+            #[cfg(feature = "serde-json-core")]
+            Self::SerdeJsonCore(e) => write!(f, "payload is not valid JSON: {}", e),
+            #[cfg(feature = "cbor")]
+            Self::Cbor(e) => write!(f, "payload is not valid CBOR: {}", e),
+            #[cfg(feature = "msgpack")]
+            Self::MsgPack(e) => write!(f, "payload is not valid MessagePack: {}", e),
+            Self::NoMatchingTopic(errors) => {
+                write!(f, "no variant matches the topic")?;
+                for (idx, VariantError { variant, error }) in errors.iter().enumerate() {
+                    let separator = if idx == 0 { ": " } else { "; " };
+                    write!(f, "{}`{}`: {}", separator, variant, error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for MqttDeserializeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Serde(e) => Some(e),
+            #[cfg(feature = "serde-json-core")]
+            Self::SerdeJsonCore(e) => Some(e),
+            #[cfg(feature = "cbor")]
+            Self::Cbor(e) => Some(e),
+            #[cfg(feature = "msgpack")]
+            Self::MsgPack(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl PartialEq<MqttDeserializeError> for MqttDeserializeError {
     fn eq(&self, other: &MqttDeserializeError) -> bool {
         match (self, other) {
+            #[cfg(feature = "serde_json")]
             (Self::Serde(_), Self::Serde(_)) => true,
+            #[cfg(feature = "serde-json-core")]
+            (Self::SerdeJsonCore(_), Self::SerdeJsonCore(_)) => true,
+            #[cfg(feature = "cbor")]
+            (Self::Cbor(_), Self::Cbor(_)) => true,
+            #[cfg(feature = "msgpack")]
+            (Self::MsgPack(_), Self::MsgPack(_)) => true,
             _ => core::mem::discriminant(self) == core::mem::discriminant(other),
         }
     }
@@ -35,10 +227,203 @@ impl From<()> for MqttDeserializeError {
     }
 }
 
+impl From<Infallible> for MqttDeserializeError {
+    fn from(e: Infallible) -> Self {
+        match e {}
+    }
+}
+
+/// An error that occurred while serializing the payload of an [`MqttItem`](crate::MqttItem)
+#[derive(Debug)]
+pub enum MqttSerializeError {
+    /// A value in the topic is not a valid topic layer
+    Topic(TopicError),
+    /// The topic or payload does not fit in the capacity of the buffer it is written to
+    CapacityExceeded,
+    /// The payload could not be written to an [`IoWriter`](crate::IoWriter)
+    #[cfg(feature = "std")]
+    Io(std::io::Error),
+    /// The payload could not be serialized as JSON
+    #[cfg(feature = "serde_json")]
+    Serde(serde_json::Error),
+    /// The payload could not be serialized as JSON by `serde_json_core`
+    #[cfg(feature = "serde-json-core")]
+    SerdeJsonCore(serde_json_core::ser::Error),
+    #[cfg(feature = "cbor")]
+    Cbor(ciborium::ser::Error<std::io::Error>),
+    #[cfg(feature = "msgpack")]
+    MsgPack(rmp_serde::encode::Error),
+}
+
+impl Display for MqttSerializeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Topic(e) => write!(f, "invalid topic: {}", e),
+            Self::CapacityExceeded => {
+                write!(f, "topic or payload exceeds the capacity of its buffer")
+            }
+            #[cfg(feature = "std")]
+            Self::Io(e) => write!(f, "payload could not be written: {}", e),
+            #[cfg(feature = "serde_json")]
+            Self::Serde(e) => write!(f, "payload could not be serialized as JSON: {}", e),
+            #[cfg(feature = "serde-json-core")]
+            Self::SerdeJsonCore(e) => write!(f, "payload could not be serialized as JSON: {}", e),
+            #[cfg(feature = "cbor")]
+            Self::Cbor(e) => write!(f, "payload could not be serialized as CBOR: {}", e),
+            #[cfg(feature = "msgpack")]
+            Self::MsgPack(e) => write!(f, "payload could not be serialized as MessagePack: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for MqttSerializeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Topic(e) => Some(e),
+            Self::CapacityExceeded => None,
+            Self::Io(e) => Some(e),
+            Self::Serde(e) => Some(e),
+            #[cfg(feature = "serde-json-core")]
+            Self::SerdeJsonCore(e) => Some(e),
+            #[cfg(feature = "cbor")]
+            Self::Cbor(e) => Some(e),
+            #[cfg(feature = "msgpack")]
+            Self::MsgPack(e) => Some(e),
+        }
+    }
+}
+
+impl From<TopicError> for MqttSerializeError {
+    fn from(e: TopicError) -> Self {
+        Self::Topic(e)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for MqttSerializeError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[cfg(feature = "serde_json")]
+impl From<serde_json::Error> for MqttSerializeError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Serde(e)
+    }
+}
+
+#[cfg(feature = "serde-json-core")]
+impl From<serde_json_core::ser::Error> for MqttSerializeError {
+    fn from(e: serde_json_core::ser::Error) -> Self {
+        Self::SerdeJsonCore(e)
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl From<ciborium::ser::Error<std::io::Error>> for MqttSerializeError {
+    fn from(e: ciborium::ser::Error<std::io::Error>) -> Self {
+        Self::Cbor(e)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+impl From<rmp_serde::encode::Error> for MqttSerializeError {
+    fn from(e: rmp_serde::encode::Error) -> Self {
+        Self::MsgPack(e)
+    }
+}
+
+impl From<Infallible> for MqttSerializeError {
+    fn from(e: Infallible) -> Self {
+        match e {}
+    }
+}
+
+#[allow(clippy::needless_lifetimes)]
 pub fn serde_json_deserialize<'a, T>(payload: &'a [u8]) -> Result<T, MqttDeserializeError>
 where
     T: DeserializeOwned,
 {
-    let payload = std::str::from_utf8(payload).map_err(|_| MqttDeserializeError::NotUtf8)?;
-    serde_json::from_str(payload).map_err(|e| MqttDeserializeError::Serde(e))
+    serde_json_deserialize_ref(payload)
+}
+
+/// Deserialize `payload` as JSON, into a value that can borrow from it
+///
+/// With the `serde-json-core` feature and without the `serde_json` feature, `payload` is
+/// deserialized with `serde_json_core`, see [`serde_json_serialize`].
+pub(crate) fn serde_json_deserialize_ref<'a, T>(
+    payload: &'a [u8],
+) -> Result<T, MqttDeserializeError>
+where
+    T: Deserialize<'a>,
+{
+    let payload = core::str::from_utf8(payload).map_err(|_| MqttDeserializeError::NotUtf8)?;
+
+    #[cfg(feature = "serde_json")]
+    {
+        serde_json::from_str(payload).map_err(MqttDeserializeError::Serde)
+    }
+
+    // Unescaped strings are never longer than the payload
+    #[cfg(all(feature = "serde-json-core", not(feature = "serde_json")))]
+    {
+        let mut unescaped = alloc::vec![0; payload.len()];
+        serde_json_core::from_str_escaped(payload, &mut unescaped)
+            .map(|(value, _len)| value)
+            .map_err(MqttDeserializeError::SerdeJsonCore)
+    }
+}
+
+#[cfg(feature = "cbor")]
+pub fn cbor_serialize<T>(
+    payload: &mut Vec<u8>,
+    value: &T,
+) -> Result<(), ciborium::ser::Error<std::io::Error>>
+where
+    T: Serialize,
+{
+    ciborium::into_writer(value, payload)
+}
+
+#[cfg(feature = "cbor")]
+pub fn cbor_deserialize<T>(payload: &[u8]) -> Result<T, MqttDeserializeError>
+where
+    T: DeserializeOwned,
+{
+    ciborium::from_reader(payload).map_err(MqttDeserializeError::Cbor)
+}
+
+/// Serialize `value` as MessagePack, encoding structs as arrays of their fields
+#[cfg(feature = "msgpack")]
+pub fn msgpack_serialize<T>(
+    payload: &mut Vec<u8>,
+    value: &T,
+) -> Result<(), rmp_serde::encode::Error>
+where
+    T: Serialize,
+{
+    rmp_serde::encode::write(payload, value)
+}
+
+/// Serialize `value` as MessagePack, encoding structs as maps of their field names to their values
+#[cfg(feature = "msgpack")]
+pub fn msgpack_named_serialize<T>(
+    payload: &mut Vec<u8>,
+    value: &T,
+) -> Result<(), rmp_serde::encode::Error>
+where
+    T: Serialize,
+{
+    rmp_serde::encode::write_named(payload, value)
+}
+
+/// Deserialize a MessagePack payload. Structs can be encoded as either arrays or maps.
+#[cfg(feature = "msgpack")]
+pub fn msgpack_deserialize<T>(payload: &[u8]) -> Result<T, MqttDeserializeError>
+where
+    T: DeserializeOwned,
+{
+    rmp_serde::from_slice(payload).map_err(MqttDeserializeError::MsgPack)
 }
@@ -1,6 +1,6 @@
 use serde::{de::DeserializeOwned, Serialize};
 
-use crate::{MqttDeserializeError, MqttItem};
+use crate::{MqttDeserializeError, MqttItem, MqttItemRef, MqttSerializeError, ToTopicAndPayload};
 
 #[derive(MqttItem, Debug, PartialEq, Clone)]
 enum MqttUpdate {
@@ -29,66 +29,120 @@ enum MqttUpdate {
     Variant5 { name: String, id: Option<u32> },
 }
 
-// #[derive(MqttItem, Debug, PartialEq, Clone)]
-// #[mqtt_item(
-//     deserialize_using = "zero_bytes_option_deser",
-//     serialize_using = "zero_bytes_option_ser"
-// )]
-// enum MqttUpdateOverideAll {
-//     #[mqtt_item(topic = "single/hello/world/<name>", payload = "<id>")]
-//     Variant5 { name: String, id: Option<u32> },
-// }
+#[derive(MqttItem, Debug, PartialEq, Clone)]
+enum FormatUpdate {
+    #[mqtt_item(
+        topic = "v6/<id>/<name>/text",
+        payload = "<payload>",
+        payload_format = "text"
+    )]
+    Text {
+        name: String,
+        id: u32,
+        payload: String,
+    },
+    #[mqtt_item(
+        topic = "v7/hello/world/<0>/text",
+        payload = "<1>",
+        payload_format = "text"
+    )]
+    Number(u32, f64),
+    #[mqtt_item(
+        topic = "v8/hello/world/<0>/raw",
+        payload = "<1>",
+        payload_format = "raw"
+    )]
+    Raw(u32, Vec<u8>),
+}
+
+#[derive(MqttItem, Debug, PartialEq, Clone)]
+#[mqtt_item(
+    deserialize_using = "zero_bytes_option_deser",
+    serialize_using = "zero_bytes_option_ser"
+)]
+enum MqttUpdateOverideAll {
+    #[mqtt_item(topic = "v5/hello/world/<name>", payload = "<id>")]
+    Variant5 { name: String, id: Option<u32> },
+    #[mqtt_item(
+        topic = "v6/hello/world/<name>",
+        payload = "<id>",
+        deserialize_using = "crate::serde_json_deserialize",
+        serialize_using = "crate::serde_json_serialize"
+    )]
+    Variant6 { name: String, id: Option<u32> },
+    #[mqtt_item(topic = "v7/hello/world/<name>")]
+    Variant7 { name: String },
+}
+
+/// Whether `error` is an error of the JSON backend
+fn is_json_error(error: &MqttDeserializeError) -> bool {
+    #[cfg(feature = "serde_json")]
+    return matches!(error, MqttDeserializeError::Serde(_));
+    #[cfg(not(feature = "serde_json"))]
+    return matches!(error, MqttDeserializeError::SerdeJsonCore(_));
+}
 
-pub fn zero_bytes_option_deser<'a, T>(payload: &'a [u8]) -> Result<Option<T>, MqttDeserializeError>
+pub fn zero_bytes_option_deser<T>(payload: &[u8]) -> Result<Option<T>, MqttDeserializeError>
 where
     T: DeserializeOwned,
 {
-    if payload.len() == 0 {
+    if payload.is_empty() {
         Ok(None)
     } else {
-        let payload = std::str::from_utf8(payload).map_err(|_| MqttDeserializeError::NotUtf8)?;
-        let val = serde_json::from_str(payload).map_err(|e| MqttDeserializeError::Serde(e))?;
-        Ok(Some(val))
+        crate::serde_json_deserialize(payload).map(Some)
     }
 }
 
 pub fn zero_bytes_option_ser<T>(
     payload: &mut Vec<u8>,
     value: &Option<T>,
-) -> Result<(), serde_json::Error>
+) -> Result<(), MqttSerializeError>
 where
     T: Serialize,
 {
     if let Some(value) = value {
-        let string = serde_json::to_string(value)?;
-        let bytes = string.as_bytes();
-        bytes.iter().for_each(|val| payload.push(*val));
-        Ok(())
-    } else {
-        Ok(())
+        crate::serde_json_serialize(payload, value)?;
     }
+    Ok(())
 }
 
-// #[test]
-// fn global_clearable() {
-//     use crate::Topic;
+#[test]
+fn global_clearable() {
+    use crate::Topic;
 
-//     let topic = Topic::from_str("v5/hello/world/steve");
-//     let payload = b"";
-//     let val = MqttUpdateOverideAll::from_topic_and_payload(topic, payload).unwrap();
+    let topic = Topic::from_str("v5/hello/world/steve");
+    let payload = b"";
+    let val = MqttUpdateOverideAll::from_topic_and_payload(topic, payload).unwrap();
 
-//     let correct = MqttUpdateOverideAll::Variant5 {
-//         name: "steve".into(),
-//         id: None,
-//     };
+    let correct = MqttUpdateOverideAll::Variant5 {
+        name: "steve".into(),
+        id: None,
+    };
 
-//     assert_eq!(correct, val);
-// }
+    assert_eq!(correct, val);
+
+    // The variant-level override takes precedence over the enum-wide default
+    let (topic, payload) = MqttUpdateOverideAll::Variant6 {
+        name: "steve".into(),
+        id: None,
+    }
+    .into_topic_and_payload()
+    .unwrap();
+    assert_eq!(topic.str(), "v6/hello/world/steve");
+    assert_eq!(std::str::from_utf8(&payload).unwrap(), "null");
+
+    let (topic, payload) = MqttUpdateOverideAll::Variant7 {
+        name: "steve".into(),
+    }
+    .into_topic_and_payload()
+    .unwrap();
+    assert_eq!(topic.str(), "v7/hello/world/steve");
+    assert!(payload.is_empty());
+}
 
 #[test]
 fn clearable() {
     use crate::Topic;
-    use MqttUpdate::*;
 
     let topic = Topic::from_str("v5/hello/world/steve");
     let payload = b"";
@@ -151,3 +205,1642 @@ fn ordering() {
         v4, "v4/hello/world/4", ""
     };
 }
+
+#[test]
+fn payload_formats() {
+    let text = FormatUpdate::Text {
+        name: "name6".into(),
+        id: 6,
+        payload: "ON".into(),
+    };
+    let (topic, payload) = text.clone().into_topic_and_payload().unwrap();
+    assert_eq!(topic.str(), "v6/6/name6/text");
+    assert_eq!(payload, b"ON");
+    assert_eq!(
+        Ok(text),
+        FormatUpdate::from_topic_and_payload(topic, &payload)
+    );
+
+    let (topic, payload) = FormatUpdate::Number(7, 21.5)
+        .into_topic_and_payload()
+        .unwrap();
+    assert_eq!(payload, b"21.5");
+    assert_eq!(
+        Ok(FormatUpdate::Number(7, 21.5)),
+        FormatUpdate::from_topic_and_payload(topic, &payload)
+    );
+
+    let raw = vec![0xde, 0xad, 0xbe, 0xef];
+    let (topic, payload) = FormatUpdate::Raw(8, raw.clone())
+        .into_topic_and_payload()
+        .unwrap();
+    assert_eq!(payload, raw);
+    assert_eq!(
+        Ok(FormatUpdate::Raw(8, raw)),
+        FormatUpdate::from_topic_and_payload(topic, &payload)
+    );
+}
+
+#[derive(MqttItem, Debug, PartialEq, Clone)]
+#[mqtt_item(topic = "device/<id>/temp", payload = "<value>")]
+struct Temperature {
+    id: u32,
+    value: f32,
+}
+
+#[derive(MqttItem, Debug, PartialEq, Clone)]
+#[mqtt_item(topic = "device/<0>/online")]
+struct Online(u32);
+
+#[test]
+fn structs() {
+    let temp = Temperature {
+        id: 12,
+        value: 21.5,
+    };
+
+    let (topic, payload) = temp.clone().into_topic_and_payload().unwrap();
+    assert_eq!(topic.str(), "device/12/temp");
+    assert_eq!(std::str::from_utf8(&payload).unwrap(), "21.5");
+    assert_eq!(
+        Ok(temp),
+        Temperature::from_topic_and_payload(topic, &payload)
+    );
+
+    let (topic, payload) = Online(3).into_topic_and_payload().unwrap();
+    assert_eq!(topic.str(), "device/3/online");
+    assert!(payload.is_empty());
+    assert_eq!(
+        Ok(Online(3)),
+        Online::from_topic_and_payload(topic, &payload)
+    );
+
+    assert_eq!(Temperature::all_generic_topics(), &["device/+/temp"]);
+    assert_eq!(
+        Err(MqttDeserializeError::UnexpectedTopicLayer(
+            "temp".into(),
+            "humidity".into()
+        )),
+        Temperature::from_topic_and_payload(crate::Topic::from_str("device/12/humidity"), b"1")
+    );
+}
+
+#[derive(MqttItem, Debug, PartialEq, Clone)]
+enum DeviceMsg {
+    #[mqtt_item(topic = "device/<0>/temp", payload = "<1>")]
+    Temperature(u32, f32),
+    #[mqtt_item(topic = "device/<0>/online")]
+    Online(u32),
+}
+
+#[derive(MqttItem, Debug, PartialEq, Clone)]
+#[mqtt_item(by_ref)]
+enum Site {
+    #[mqtt_item(topic = "site/<1>", extend_into)]
+    Device(DeviceMsg, u32),
+    #[mqtt_item(topic = "site/<id>/gateway")]
+    Gateway {
+        id: u32,
+        #[mqtt_item(extend_into)]
+        msg: DeviceMsg,
+    },
+}
+
+#[test]
+fn extend_into() {
+    let msg = Site::Device(DeviceMsg::Temperature(3, 21.5), 7);
+    let (topic, payload) = msg.clone().into_topic_and_payload().unwrap();
+    assert_eq!(topic.str(), "site/7/device/3/temp");
+    assert_eq!(std::str::from_utf8(&payload).unwrap(), "21.5");
+    assert_eq!(Ok(msg), Site::from_topic_and_payload(topic, &payload));
+
+    let msg = Site::Gateway {
+        id: 2,
+        msg: DeviceMsg::Online(4),
+    };
+    let (topic, payload) = msg.clone().into_topic_and_payload().unwrap();
+    assert_eq!(topic.str(), "site/2/gateway/device/4/online");
+    assert!(payload.is_empty());
+    assert_eq!(Ok(msg), Site::from_topic_and_payload(topic, &payload));
+
+    assert_eq!(
+        Site::all_generic_topics(),
+        &[
+            "site/+/device/+/temp",
+            "site/+/device/+/online",
+            "site/+/gateway/device/+/temp",
+            "site/+/gateway/device/+/online",
+        ]
+    );
+}
+
+#[derive(MqttItem, Debug, PartialEq, Clone)]
+enum Sensor {
+    #[mqtt_item(topic = "temp/<0>", payload = "<1>")]
+    Temperature(u32, f32),
+    #[mqtt_item(topic = "door", payload = "<0>")]
+    Door(bool),
+}
+
+#[derive(MqttItem, Debug, PartialEq, Clone)]
+#[mqtt_item(topic = "plant/<line>/<sensor>/status")]
+struct PlantStatus {
+    line: String,
+    #[mqtt_item(layer)]
+    sensor: Sensor,
+}
+
+#[test]
+fn layer() {
+    let status = PlantStatus {
+        line: "north".into(),
+        sensor: Sensor::Temperature(2, 80.5),
+    };
+    let (topic, payload) = status.clone().into_topic_and_payload().unwrap();
+    assert_eq!(topic.str(), "plant/north/temp/2/status");
+    assert_eq!(std::str::from_utf8(&payload).unwrap(), "80.5");
+    assert_eq!(
+        Ok(status),
+        PlantStatus::from_topic_and_payload(topic, &payload)
+    );
+
+    let status = PlantStatus {
+        line: "south".into(),
+        sensor: Sensor::Door(true),
+    };
+    let (topic, payload) = status.clone().into_topic_and_payload().unwrap();
+    assert_eq!(topic.str(), "plant/south/door/status");
+    assert_eq!(
+        Ok(status),
+        PlantStatus::from_topic_and_payload(topic, &payload)
+    );
+
+    assert_eq!(
+        Err(MqttDeserializeError::MissingTopicLayer("sensor".into())),
+        PlantStatus::from_topic_and_payload(crate::Topic::from_str("plant/north/status"), b"")
+    );
+
+    assert_eq!(
+        PlantStatus::all_generic_topics(),
+        &["plant/+/temp/+/status", "plant/+/door/status"]
+    );
+}
+
+#[derive(Debug, PartialEq)]
+enum AppDeserializeError {
+    Mqtt(MqttDeserializeError),
+    OutOfRange(u8),
+}
+
+impl From<MqttDeserializeError> for AppDeserializeError {
+    fn from(e: MqttDeserializeError) -> Self {
+        Self::Mqtt(e)
+    }
+}
+
+#[derive(Debug)]
+struct AppSerializeError;
+
+impl From<MqttSerializeError> for AppSerializeError {
+    fn from(_: MqttSerializeError) -> Self {
+        Self
+    }
+}
+
+#[derive(MqttItem, Debug, PartialEq, Clone)]
+#[mqtt_item(
+    deserialize_error_type = "AppDeserializeError",
+    serialize_error_type = "AppSerializeError"
+)]
+enum AppUpdate {
+    #[mqtt_item(topic = "app/<0>", payload = "<1>")]
+    Value(u32, u32),
+    #[mqtt_item(topic = "app/device", extend_into)]
+    Device(DeviceMsg),
+}
+
+#[test]
+fn custom_error_types() {
+    let (topic, payload) = AppUpdate::Value(1, 2).into_topic_and_payload().unwrap();
+    assert_eq!(
+        Ok(AppUpdate::Value(1, 2)),
+        AppUpdate::from_topic_and_payload(topic, &payload)
+    );
+
+    let (topic, payload) = AppUpdate::Device(DeviceMsg::Online(5))
+        .into_topic_and_payload()
+        .unwrap();
+    assert_eq!(topic.str(), "app/device/device/5/online");
+    assert_eq!(
+        Ok(AppUpdate::Device(DeviceMsg::Online(5))),
+        AppUpdate::from_topic_and_payload(topic, &payload)
+    );
+
+    assert_eq!(
+        Err(AppDeserializeError::Mqtt(
+            MqttDeserializeError::NoMatchingTopic(Vec::new())
+        )),
+        AppUpdate::from_topic_and_payload(crate::Topic::from_str("other/1"), b"2")
+    );
+}
+
+/// Deserialize a percentage, rejecting values above 100 with an application error
+fn percentage_deser(payload: &[u8]) -> Result<u8, AppDeserializeError> {
+    let value: u8 = crate::serde_json_deserialize(payload)?;
+    if value > 100 {
+        return Err(AppDeserializeError::OutOfRange(value));
+    }
+    Ok(value)
+}
+
+#[derive(MqttItem, Debug, PartialEq, Clone)]
+#[mqtt_item(
+    deserialize_error_type = "AppDeserializeError",
+    serialize_error_type = "AppSerializeError"
+)]
+enum AppLight {
+    #[mqtt_item(
+        topic = "light/<0>/brightness",
+        payload = "<1>",
+        deserialize_using = "percentage_deser"
+    )]
+    Brightness(u32, u8),
+    #[mqtt_item(topic = "light/app", extend_into)]
+    App(AppUpdate),
+}
+
+#[test]
+fn custom_deserialize_errors() {
+    let (topic, payload) = AppLight::Brightness(1, 80)
+        .into_topic_and_payload()
+        .unwrap();
+    assert_eq!(
+        Ok(AppLight::Brightness(1, 80)),
+        AppLight::from_topic_and_payload(topic, &payload)
+    );
+    assert_eq!(
+        Err(AppDeserializeError::OutOfRange(150)),
+        AppLight::from_topic_and_payload(crate::Topic::from_str("light/1/brightness"), b"150")
+    );
+
+    // The errors of nested items keep their type
+    let (topic, payload) = AppLight::App(AppUpdate::Value(1, 2))
+        .into_topic_and_payload()
+        .unwrap();
+    assert_eq!(
+        Ok(AppLight::App(AppUpdate::Value(1, 2))),
+        AppLight::from_topic_and_payload(topic, &payload)
+    );
+    assert!(matches!(
+        AppLight::from_topic_and_payload(crate::Topic::from_str("light/app/app/1"), b"x"),
+        Err(AppDeserializeError::Mqtt(error)) if is_json_error(&error)
+    ));
+
+    // A topic that no nested variant matches is still a topic mismatch
+    let error = AppLight::from_topic_and_payload(crate::Topic::from_str("light/app/other"), b"2")
+        .unwrap_err();
+    let errors = match &error {
+        AppDeserializeError::Mqtt(MqttDeserializeError::NoMatchingTopic(errors)) => errors,
+        _ => panic!("unexpected error {:?}", error),
+    };
+    assert_eq!(
+        errors.iter().map(|e| e.variant).collect::<Vec<_>>(),
+        ["Brightness", "App"]
+    );
+}
+
+#[derive(MqttItem, Debug, PartialEq, Clone)]
+#[mqtt_item(codec = "crate::TextCodec")]
+enum Switch {
+    #[mqtt_item(topic = "switch/<0>/state", payload = "<1>")]
+    State(u32, String),
+    #[mqtt_item(topic = "switch/<0>/level", payload = "<1>")]
+    Level(u32, f32),
+    #[mqtt_item(
+        topic = "switch/<0>/config",
+        payload = "<1>",
+        codec = "crate::JsonCodec"
+    )]
+    Config(u32, Vec<u32>),
+    #[mqtt_item(
+        topic = "switch/<0>/firmware",
+        payload = "<1>",
+        codec = "crate::RawCodec"
+    )]
+    Firmware(u32, Vec<u8>),
+}
+
+#[test]
+fn codecs() {
+    macro_rules! test {
+        ($input: expr, $topic: expr, $payload: expr) => {
+            let (topic, payload) = $input.clone().into_topic_and_payload().unwrap();
+            assert_eq!(topic.str(), $topic);
+            assert_eq!(payload.as_slice(), $payload);
+
+            let out = Switch::from_topic_and_payload(topic, &payload);
+            assert_eq!(Ok($input), out);
+        };
+    }
+
+    test!(Switch::State(1, "ON".into()), "switch/1/state", b"ON");
+    test!(Switch::Level(2, 21.5), "switch/2/level", b"21.5");
+    test!(Switch::Config(3, vec![1, 2]), "switch/3/config", b"[1,2]");
+    test!(
+        Switch::Firmware(4, vec![0, 159, 146, 150]),
+        "switch/4/firmware",
+        &[0, 159, 146, 150]
+    );
+
+    assert_eq!(
+        Err(MqttDeserializeError::InvalidPayload("high".into())),
+        Switch::from_topic_and_payload(crate::Topic::from_str("switch/2/level"), b"high")
+    );
+}
+
+#[cfg(feature = "cbor")]
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone)]
+struct Reading {
+    temperature: f32,
+    battery: u8,
+}
+
+#[cfg(feature = "cbor")]
+#[derive(MqttItem, Debug, PartialEq, Clone)]
+#[mqtt_item(payload_format = "cbor")]
+enum CborUpdate {
+    #[mqtt_item(topic = "sensor/<0>/reading", payload = "<1>")]
+    Reading(u32, Reading),
+    #[mqtt_item(topic = "sensor/<0>/name", payload = "<1>", payload_format = "json")]
+    Name(u32, String),
+}
+
+#[cfg(feature = "cbor")]
+#[test]
+fn cbor() {
+    let reading = CborUpdate::Reading(
+        1,
+        Reading {
+            temperature: 21.5,
+            battery: 80,
+        },
+    );
+    let (topic, payload) = reading.clone().into_topic_and_payload().unwrap();
+    assert_eq!(topic.str(), "sensor/1/reading");
+    let mut expected = Vec::new();
+    ciborium::into_writer(
+        &Reading {
+            temperature: 21.5,
+            battery: 80,
+        },
+        &mut expected,
+    )
+    .unwrap();
+    assert_eq!(payload, expected);
+    assert_write_payload_matches(reading.clone());
+    assert_eq!(
+        Ok(reading),
+        CborUpdate::from_topic_and_payload(topic, &payload)
+    );
+
+    let (_, payload) = CborUpdate::Name(1, "kitchen".into())
+        .into_topic_and_payload()
+        .unwrap();
+    assert_eq!(payload, br#""kitchen""#);
+
+    // A lone break code is not a `Reading`
+    assert!(matches!(
+        crate::cbor_deserialize::<Reading>(b"\xff"),
+        Err(MqttDeserializeError::Cbor(ciborium::de::Error::Semantic(
+            _,
+            _
+        )))
+    ));
+    // Arrays nested deeper than ciborium's recursion limit are rejected
+    assert!(matches!(
+        crate::cbor_deserialize::<ciborium::value::Value>(&[0x81; 512]),
+        Err(MqttDeserializeError::Cbor(
+            ciborium::de::Error::RecursionLimitExceeded
+        ))
+    ));
+}
+
+#[cfg(feature = "msgpack")]
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone)]
+struct GatewayStatus {
+    uptime: u32,
+    clients: u8,
+}
+
+#[cfg(feature = "msgpack")]
+#[derive(MqttItem, Debug, PartialEq, Clone)]
+#[mqtt_item(payload_format = "msgpack")]
+enum GatewayUpdate {
+    #[mqtt_item(topic = "gateway/<0>/status", payload = "<1>")]
+    Status(u32, GatewayStatus),
+    #[mqtt_item(
+        topic = "gateway/<0>/status_named",
+        payload = "<1>",
+        payload_format = "msgpack_named"
+    )]
+    StatusNamed(u32, GatewayStatus),
+}
+
+#[cfg(feature = "msgpack")]
+#[test]
+fn msgpack() {
+    let status = GatewayStatus {
+        uptime: 10,
+        clients: 2,
+    };
+
+    let update = GatewayUpdate::Status(1, status.clone());
+    let (topic, payload) = update.clone().into_topic_and_payload().unwrap();
+    assert_eq!(payload, rmp_serde::to_vec(&status).unwrap());
+    assert_eq!(
+        Ok(update),
+        GatewayUpdate::from_topic_and_payload(topic, &payload)
+    );
+
+    let update = GatewayUpdate::StatusNamed(1, status.clone());
+    assert_write_payload_matches(update.clone());
+    let (topic, payload) = update.clone().into_topic_and_payload().unwrap();
+    assert_eq!(payload, rmp_serde::to_vec_named(&status).unwrap());
+    assert_eq!(
+        Ok(update),
+        GatewayUpdate::from_topic_and_payload(topic, &payload)
+    );
+
+    assert!(matches!(
+        crate::msgpack_deserialize::<GatewayStatus>(b"\xc1"),
+        Err(MqttDeserializeError::MsgPack(_))
+    ));
+}
+
+#[cfg(feature = "bytes")]
+#[derive(MqttItem, Debug, PartialEq, Clone)]
+#[mqtt_item(
+    topic = "camera/<id>/frame",
+    payload = "<frame>",
+    payload_format = "raw"
+)]
+struct Frame {
+    id: u32,
+    frame: bytes::Bytes,
+}
+
+#[cfg(feature = "bytes")]
+#[test]
+fn raw_bytes() {
+    let frame = Frame {
+        id: 1,
+        frame: bytes::Bytes::from_static(&[1, 2, 3]),
+    };
+    let (topic, payload) = frame.clone().into_topic_and_payload().unwrap();
+    assert_eq!(payload, [1, 2, 3]);
+    assert_eq!(Ok(frame), Frame::from_topic_and_payload(topic, &payload));
+}
+
+#[derive(MqttItem, Debug, PartialEq, Clone)]
+enum Command {
+    #[mqtt_item(topic = "cmd/reboot")]
+    Reboot,
+    #[mqtt_item(topic = "cmd/ping", ignore_payload)]
+    Ping,
+    #[mqtt_item(topic = "cmd/<0>/restart")]
+    Restart(String),
+}
+
+#[derive(MqttItem, Debug, PartialEq, Clone)]
+#[mqtt_item(topic = "cmd/shutdown")]
+struct Shutdown;
+
+#[test]
+fn unit_variants() {
+    let (topic, payload) = Command::Reboot.into_topic_and_payload().unwrap();
+    assert_eq!(topic.str(), "cmd/reboot");
+    assert!(payload.is_empty());
+    assert_eq!(
+        Ok(Command::Reboot),
+        Command::from_topic_and_payload(topic.clone(), &payload)
+    );
+    assert!(Command::from_topic_and_payload(topic, b"now").is_err());
+
+    let (topic, _) = Command::Ping.into_topic_and_payload().unwrap();
+    assert_eq!(
+        Ok(Command::Ping),
+        Command::from_topic_and_payload(topic, b"ignored")
+    );
+
+    let (topic, payload) = Shutdown.into_topic_and_payload().unwrap();
+    assert_eq!(topic.str(), "cmd/shutdown");
+    assert!(payload.is_empty());
+    assert_eq!(Ok(Shutdown), Shutdown::from_topic_and_payload(topic, b""));
+    assert_eq!(
+        Err(MqttDeserializeError::InvalidPayload("now".into())),
+        Shutdown::from_topic_and_payload(crate::Topic::from_str("cmd/shutdown"), b"now")
+    );
+
+    assert_eq!(
+        Command::all_generic_topics(),
+        &["cmd/reboot", "cmd/ping", "cmd/+/restart"]
+    );
+}
+
+#[derive(MqttItem, Debug, PartialEq, Clone)]
+enum Climate {
+    #[mqtt_item(topic = "climate/<room>", payload = "{<temp>, <humidity>}")]
+    Reading {
+        room: String,
+        temp: f32,
+        humidity: u8,
+    },
+    #[mqtt_item(topic = "climate/<0>/limits")]
+    Limits(String, #[mqtt_item(payload)] f32, #[mqtt_item(payload)] f32),
+}
+
+#[test]
+fn multi_field_payloads() {
+    let reading = Climate::Reading {
+        room: "kitchen".into(),
+        temp: 21.5,
+        humidity: 40,
+    };
+    let (topic, payload) = reading.clone().into_topic_and_payload().unwrap();
+    assert_eq!(topic.str(), "climate/kitchen");
+    assert_eq!(
+        std::str::from_utf8(&payload).unwrap(),
+        r#"{"temp":21.5,"humidity":40}"#
+    );
+    assert_eq!(
+        Ok(reading),
+        Climate::from_topic_and_payload(topic, &payload)
+    );
+
+    let limits = Climate::Limits("kitchen".into(), 18.0, 24.5);
+    let (topic, payload) = limits.clone().into_topic_and_payload().unwrap();
+    assert_eq!(topic.str(), "climate/kitchen/limits");
+    assert_eq!(
+        std::str::from_utf8(&payload).unwrap(),
+        r#"{"1":18.0,"2":24.5}"#
+    );
+    assert_eq!(Ok(limits), Climate::from_topic_and_payload(topic, &payload));
+}
+
+#[derive(MqttItem, MqttItemRef, Debug, PartialEq, Clone)]
+#[mqtt_item(topic = "measurement/<id>", payload = "{<value>, <unit>}")]
+struct Measurement<T: Serialize + DeserializeOwned> {
+    id: u32,
+    value: T,
+    unit: String,
+}
+
+#[test]
+fn generic_multi_field_payloads() {
+    let measurement = Measurement {
+        id: 3,
+        value: vec![1.5, 2.0],
+        unit: "V".into(),
+    };
+    let (topic, payload) = measurement.to_topic_and_payload().unwrap();
+    assert_eq!(topic.str(), "measurement/3");
+    assert_eq!(
+        std::str::from_utf8(&payload).unwrap(),
+        r#"{"value":[1.5,2.0],"unit":"V"}"#
+    );
+    // The length of JSON payloads is not known without serializing them
+    assert_eq!(None, measurement.payload_size_hint());
+    assert_write_matches(measurement.clone());
+    assert_eq!(
+        Ok(measurement.clone()),
+        Measurement::from_topic_and_payload_ref(&topic, &payload)
+    );
+    assert_eq!(
+        Ok(measurement),
+        Measurement::from_topic_and_payload(topic, &payload)
+    );
+}
+
+#[derive(MqttItem, Debug, PartialEq, Clone)]
+enum Bridge {
+    #[mqtt_item(topic = "bridge/<0>/<1..>", payload = "<2>", payload_format = "raw")]
+    Forward(String, crate::Topic, Vec<u8>),
+    #[mqtt_item(topic = "bridge/<remote>/status")]
+    Status { remote: String },
+    #[mqtt_item(topic = "bridge/<remote>/log/<path..>", payload = "<line>")]
+    Log {
+        remote: String,
+        path: Vec<String>,
+        line: String,
+    },
+}
+
+#[derive(MqttItem, Debug, PartialEq, Clone)]
+#[mqtt_item(topic = "plant/<line>/<bridge>/status")]
+struct PlantBridge {
+    line: String,
+    #[mqtt_item(layer)]
+    bridge: Bridge,
+}
+
+#[test]
+fn rest_layers_before_suffix() {
+    // `plant/+/bridge/+/#/status` is not a valid topic filter, so it ends at the `#`
+    assert_eq!(
+        PlantBridge::all_generic_topics(),
+        &[
+            "plant/+/bridge/+/#",
+            "plant/+/bridge/+/status/status",
+            "plant/+/bridge/+/log/#",
+        ]
+    );
+    assert_eq!(
+        crate::TopicTree::from_item::<PlantBridge>().len(),
+        PlantBridge::all_generic_topics().len()
+    );
+
+    let mut router = crate::TopicRouter::<PlantBridge>::new();
+    router.add(|bridge| bridge);
+
+    let forward = PlantBridge {
+        line: "a".into(),
+        bridge: Bridge::Forward("north".into(), crate::Topic::from_str("site/1"), vec![1]),
+    };
+    let (topic, payload) = forward.clone().into_topic_and_payload().unwrap();
+    assert_eq!(topic.str(), "plant/a/bridge/north/site/1/status");
+    assert_eq!(Some(Ok(forward)), router.dispatch(&topic, &payload));
+
+    let log = PlantBridge {
+        line: "a".into(),
+        bridge: Bridge::Log {
+            remote: "south".into(),
+            path: vec!["kernel".into()],
+            line: "attached".into(),
+        },
+    };
+    let (topic, payload) = log.clone().into_topic_and_payload().unwrap();
+    assert_eq!(topic.str(), "plant/a/bridge/south/log/kernel/status");
+    assert_eq!(Some(Ok(log)), router.dispatch(&topic, &payload));
+
+    // The filter matches topics without the suffix, which the parser rejects
+    let topic = crate::Topic::from_str("plant/a/bridge/north/site/1");
+    assert!(matches!(router.dispatch(&topic, &[1]), Some(Err(_))));
+}
+
+#[test]
+fn rest_layers() {
+    let forward = Bridge::Forward(
+        "north".into(),
+        crate::Topic::from_str("site/1/temp"),
+        vec![1, 2],
+    );
+    let (topic, payload) = forward.clone().into_topic_and_payload().unwrap();
+    assert_eq!(topic.str(), "bridge/north/site/1/temp");
+    assert_eq!(Ok(forward), Bridge::from_topic_and_payload(topic, &payload));
+
+    // Topics without a rest layer are attempted first
+    let status = Bridge::Status {
+        remote: "north".into(),
+    };
+    let (topic, payload) = status.clone().into_topic_and_payload().unwrap();
+    assert_eq!(topic.str(), "bridge/north/status");
+    assert_eq!(Ok(status), Bridge::from_topic_and_payload(topic, &payload));
+
+    let log = Bridge::Log {
+        remote: "south".into(),
+        path: vec!["kernel".into(), "usb".into()],
+        line: "attached".into(),
+    };
+    let (topic, payload) = log.clone().into_topic_and_payload().unwrap();
+    assert_eq!(topic.str(), "bridge/south/log/kernel/usb");
+    assert_eq!(Ok(log), Bridge::from_topic_and_payload(topic, &payload));
+
+    assert_eq!(
+        Bridge::all_generic_topics(),
+        &["bridge/+/#", "bridge/+/status", "bridge/+/log/#"]
+    );
+}
+
+#[test]
+fn topic_validation() {
+    let update = MqttUpdate::Variant1 {
+        name: "kitchen/+".into(),
+        id: 1,
+        payload: "payload".into(),
+    };
+    assert!(matches!(
+        update.into_topic_and_payload(),
+        Err(MqttSerializeError::Topic(
+            crate::TopicError::LayerSeparator(_)
+        ))
+    ));
+
+    let log = Bridge::Log {
+        remote: "south".into(),
+        path: vec!["kernel".into(), "#".into()],
+        line: "attached".into(),
+    };
+    let error = log.into_topic_and_payload().unwrap_err();
+    assert!(matches!(
+        error,
+        MqttSerializeError::Topic(crate::TopicError::InvalidCharacter('#'))
+    ));
+    assert_eq!(
+        error.to_string(),
+        "invalid topic: topic contains invalid character `#`"
+    );
+    #[cfg(feature = "std")]
+    assert!(std::error::Error::source(&error).is_some());
+}
+
+#[derive(Debug, PartialEq)]
+enum Incoming {
+    Site(Site),
+    Command(Command),
+    Bridge(Bridge),
+    Shutdown(Shutdown),
+}
+
+#[test]
+fn router() {
+    let mut router = crate::TopicRouter::new();
+    router
+        .add(Incoming::Site)
+        .add(Incoming::Command)
+        .add(Incoming::Bridge)
+        .add(Incoming::Shutdown);
+
+    assert_eq!(
+        router.filters().collect::<Vec<_>>(),
+        [
+            Site::all_generic_topics(),
+            Command::all_generic_topics(),
+            Bridge::all_generic_topics(),
+            Shutdown::all_generic_topics(),
+        ]
+        .concat()
+    );
+
+    macro_rules! test {
+        ($input: expr, $variant: path) => {
+            let (topic, payload) = $input.clone().into_topic_and_payload().unwrap();
+            assert_eq!(
+                Some(Ok($variant($input))),
+                router.dispatch(&topic, &payload)
+            );
+        };
+    }
+
+    test!(
+        Site::Device(DeviceMsg::Temperature(3, 21.5), 7),
+        Incoming::Site
+    );
+    test!(
+        Site::Gateway {
+            id: 2,
+            msg: DeviceMsg::Online(4),
+        },
+        Incoming::Site
+    );
+    test!(Command::Ping, Incoming::Command);
+    test!(Command::Restart("now".into()), Incoming::Command);
+    test!(Shutdown, Incoming::Shutdown);
+    test!(
+        Bridge::Status {
+            remote: "north".into()
+        },
+        Incoming::Bridge
+    );
+    test!(
+        Bridge::Log {
+            remote: "south".into(),
+            path: vec!["kernel".into()],
+            line: "attached".into(),
+        },
+        Incoming::Bridge
+    );
+    test!(
+        Bridge::Forward(
+            "north".into(),
+            crate::Topic::from_str("cmd/reboot"),
+            vec![1]
+        ),
+        Incoming::Bridge
+    );
+
+    assert_eq!(
+        Some(Err(MqttDeserializeError::InvalidPayload("now".into()))),
+        router.dispatch(&crate::Topic::from_str("cmd/shutdown"), b"now")
+    );
+    assert_eq!(
+        None,
+        router.dispatch(&crate::Topic::from_str("unknown/topic"), b"")
+    );
+}
+
+#[test]
+fn topic_tree() {
+    let tree = crate::TopicTree::from_item::<Site>();
+    assert_eq!(tree.len(), Site::all_generic_topics().len());
+    assert_eq!(
+        tree.to_string(),
+        "site\n  +\n    device\n      +\n        temp\n        online\n    gateway\n      device\n        +\n          temp\n          online\n"
+    );
+}
+
+#[test]
+fn deserialize_errors() {
+    use crate::VariantError;
+
+    let error = DeviceMsg::from_topic_and_payload(crate::Topic::from_str("device/x/temp"), b"1")
+        .unwrap_err();
+    let errors = match &error {
+        MqttDeserializeError::NoMatchingTopic(errors) => errors,
+        _ => panic!("unexpected error {:?}", error),
+    };
+    assert!(matches!(
+        errors.as_slice(),
+        [
+            VariantError {
+                variant: "Temperature",
+                error: MqttDeserializeError::InvalidTopicLayer(field, layer),
+            },
+            VariantError {
+                variant: "Online",
+                error: MqttDeserializeError::InvalidTopicLayer(_, _),
+            },
+        ] if field == "0" && layer == "x"
+    ));
+    assert_eq!(
+        error.to_string(),
+        "no variant matches the topic: \
+        `Temperature`: topic layer `x` is not a valid `0`; \
+        `Online`: topic layer `x` is not a valid `0`"
+    );
+
+    let error =
+        Temperature::from_topic_and_payload(crate::Topic::from_str("device/1/temp"), b"hot")
+            .unwrap_err();
+    assert!(error.to_string().starts_with("payload is not valid JSON: "));
+    #[cfg(feature = "std")]
+    assert!(std::error::Error::source(&error).is_some());
+}
+
+#[test]
+fn payload_errors() {
+    // The topic matches `Variant1`, so its payload error is returned
+    let error = MqttUpdate::from_topic_and_payload(crate::Topic::from_str("1/name1"), b"not json")
+        .unwrap_err();
+    assert!(is_json_error(&error));
+    assert!(!error.is_topic_mismatch());
+
+    // Errors of nested items are surfaced as well
+    let error =
+        Site::from_topic_and_payload(crate::Topic::from_str("site/7/device/3/temp"), b"hot")
+            .unwrap_err();
+    assert!(is_json_error(&error));
+
+    let error =
+        Site::from_topic_and_payload(crate::Topic::from_str("other/7/device"), b"").unwrap_err();
+    assert!(matches!(error, MqttDeserializeError::NoMatchingTopic(_)));
+    assert!(error.is_topic_mismatch());
+
+    // Topics with more layers than the topic of an item don't match it
+    assert_eq!(
+        Err(MqttDeserializeError::UnknownLayer),
+        Temperature::from_topic_and_payload(crate::Topic::from_str("device/12/temp/raw"), b"1")
+    );
+}
+
+/// An item that implements `MqttItem` by hand, and tells topics that don't belong to it apart
+/// from invalid payloads
+#[derive(Debug, PartialEq)]
+struct Uptime(u64);
+
+impl MqttItem for Uptime {
+    type DeserializeError = MqttDeserializeError;
+    type SerializeError = MqttSerializeError;
+
+    #[allow(clippy::extra_unused_lifetimes)]
+    fn from_topic_and_payload<'a>(
+        topic: crate::Topic,
+        payload: &[u8],
+    ) -> Result<Self, Self::DeserializeError> {
+        Self::parse_topic_and_payload(topic, payload).map_err(crate::ParseError::into_error)
+    }
+
+    fn parse_topic_and_payload(
+        topic: crate::Topic,
+        payload: &[u8],
+    ) -> Result<Self, crate::ParseError<Self::DeserializeError>> {
+        if topic.str() != "uptime" {
+            return Err(crate::ParseError::TopicMismatch(
+                MqttDeserializeError::UnexpectedTopicLayer("uptime".into(), topic.str().into()),
+            ));
+        }
+        crate::serde_json_deserialize(payload)
+            .map(Uptime)
+            .map_err(crate::ParseError::Invalid)
+    }
+
+    fn push_topic_and_payload(
+        self,
+        topic: &mut crate::Topic,
+        payload: &mut Vec<u8>,
+    ) -> Result<(), Self::SerializeError> {
+        topic.push("uptime");
+        crate::serde_json_serialize(payload, &self.0)?;
+        Ok(())
+    }
+
+    fn all_generic_topics() -> &'static [&'static str] {
+        &["uptime"]
+    }
+}
+
+#[derive(MqttItem, Debug, PartialEq)]
+enum BrokerStatus {
+    #[mqtt_item(topic = "broker", extend_into)]
+    Uptime(Uptime),
+    #[mqtt_item(topic = "broker/<0>")]
+    Other(String),
+}
+
+#[test]
+fn nested_hand_written_errors() {
+    let (topic, payload) = BrokerStatus::Uptime(Uptime(5))
+        .into_topic_and_payload()
+        .unwrap();
+    assert_eq!(topic.str(), "broker/uptime");
+    assert_eq!(
+        Ok(BrokerStatus::Uptime(Uptime(5))),
+        BrokerStatus::from_topic_and_payload(topic, &payload)
+    );
+
+    // The payload of the nested item is invalid, so its error is returned
+    assert!(matches!(
+        BrokerStatus::from_topic_and_payload(crate::Topic::from_str("broker/uptime"), b"x"),
+        Err(error) if is_json_error(&error)
+    ));
+
+    // The topic does not belong to the nested item, so the other variants are tried
+    assert_eq!(
+        Ok(BrokerStatus::Other("load".into())),
+        BrokerStatus::from_topic_and_payload(crate::Topic::from_str("broker/load"), b"")
+    );
+}
+
+#[derive(serde::Deserialize, Debug, PartialEq)]
+struct Sample<'a> {
+    unit: &'a str,
+    value: f32,
+}
+
+#[derive(MqttItemRef, Debug, PartialEq)]
+enum Telemetry<'a> {
+    #[mqtt_item(topic = "telemetry/<device>/sample/<sensor>", payload = "<sample>")]
+    Sample {
+        device: &'a str,
+        sensor: &'a str,
+        sample: Sample<'a>,
+    },
+    #[mqtt_item(topic = "telemetry/<0>/log", payload = "<1>", payload_format = "raw")]
+    Log(&'a str, &'a str),
+    #[mqtt_item(
+        topic = "telemetry/<0>/blob/<1..>",
+        payload = "<2>",
+        payload_format = "raw"
+    )]
+    Blob(&'a str, Vec<&'a str>, &'a [u8]),
+}
+
+#[derive(MqttItemRef, Debug, PartialEq)]
+#[mqtt_item(topic = "site/<site>/<telemetry>")]
+struct SiteTelemetry<'a> {
+    site: u32,
+    #[mqtt_item(layer)]
+    telemetry: Telemetry<'a>,
+}
+
+#[derive(MqttItemRef, Debug, PartialEq)]
+#[mqtt_item(topic = "gateway/<telemetry>/<gateway>")]
+struct GatewayTelemetry<'a> {
+    #[mqtt_item(layer)]
+    telemetry: Telemetry<'a>,
+    gateway: &'a str,
+}
+
+#[test]
+fn borrowed_items() {
+    let topic = crate::Topic::from_str("telemetry/dev1/sample/temp");
+    let payload = br#"{"unit":"C","value":21.5}"#;
+    assert_eq!(
+        Ok(Telemetry::Sample {
+            device: "dev1",
+            sensor: "temp",
+            sample: Sample {
+                unit: "C",
+                value: 21.5
+            },
+        }),
+        Telemetry::from_topic_and_payload_ref(&topic, payload)
+    );
+
+    let topic = crate::Topic::from_str("telemetry/dev1/log");
+    assert_eq!(
+        Ok(Telemetry::Log("dev1", "booted")),
+        Telemetry::from_topic_and_payload_ref(&topic, b"booted")
+    );
+
+    let topic = crate::Topic::from_str("telemetry/dev1/blob/fw/v2");
+    assert_eq!(
+        Ok(Telemetry::Blob("dev1", vec!["fw", "v2"], &[0xff, 0x00])),
+        Telemetry::from_topic_and_payload_ref(&topic, &[0xff, 0x00])
+    );
+
+    let topic = crate::Topic::from_str("site/4/telemetry/dev1/log");
+    assert_eq!(
+        Ok(SiteTelemetry {
+            site: 4,
+            telemetry: Telemetry::Log("dev1", "booted"),
+        }),
+        SiteTelemetry::from_topic_and_payload_ref(&topic, b"booted")
+    );
+
+    // The layers after the nested item are split off without collecting them
+    let topic = crate::Topic::from_str("gateway/telemetry/dev1/blob/fw/v2/gw2");
+    assert_eq!(
+        Ok(GatewayTelemetry {
+            telemetry: Telemetry::Blob("dev1", vec!["fw", "v2"], &[0xff]),
+            gateway: "gw2",
+        }),
+        GatewayTelemetry::from_topic_and_payload_ref(&topic, &[0xff])
+    );
+    let topic = crate::Topic::from_str("gateway/gw2");
+    assert_eq!(
+        Err(MqttDeserializeError::MissingTopicLayer("telemetry".into())),
+        GatewayTelemetry::from_topic_and_payload_ref(&topic, b"")
+    );
+
+    // The topic matches `Log`, so its payload error is returned
+    let topic = crate::Topic::from_str("telemetry/dev1/log");
+    assert_eq!(
+        Err(MqttDeserializeError::NotUtf8),
+        Telemetry::from_topic_and_payload_ref(&topic, &[0xff])
+    );
+
+    let topic = crate::Topic::from_str("other/dev1/log");
+    assert!(Telemetry::from_topic_and_payload_ref(&topic, b"")
+        .unwrap_err()
+        .is_topic_mismatch());
+}
+
+#[test]
+fn serialize_by_reference() {
+    let items = [
+        MqttUpdate::Variant1 {
+            name: "name1".into(),
+            id: 1,
+            payload: "payload".into(),
+        },
+        MqttUpdate::Variant5 {
+            name: "name5".into(),
+            id: Some(5),
+        },
+    ];
+    for item in items.iter() {
+        assert_eq!(
+            item.clone().into_topic_and_payload().unwrap(),
+            item.to_topic_and_payload().unwrap()
+        );
+    }
+    let raw = FormatUpdate::Raw(8, vec![1, 2, 3]);
+    assert_eq!(
+        raw.clone().into_topic_and_payload().unwrap(),
+        raw.to_topic_and_payload().unwrap()
+    );
+
+    // Nested items, multi-field payloads and remaining layers are serialized by reference too
+    let site = Site::Gateway {
+        id: 3,
+        msg: DeviceMsg::Temperature(4, 21.5),
+    };
+    let (topic, payload) = site.to_topic_and_payload().unwrap();
+    assert_eq!(Ok(site), Site::from_topic_and_payload(topic, &payload));
+
+    let reading = Climate::Reading {
+        room: "kitchen".into(),
+        temp: 21.5,
+        humidity: 40,
+    };
+    let (topic, payload) = reading.to_topic_and_payload().unwrap();
+    assert_eq!(
+        Ok(reading),
+        Climate::from_topic_and_payload(topic, &payload)
+    );
+
+    let log = Bridge::Log {
+        remote: "south".into(),
+        path: vec!["kernel".into(), "usb".into()],
+        line: "attached".into(),
+    };
+    let (topic, payload) = log.to_topic_and_payload().unwrap();
+    assert_eq!(topic.str(), "bridge/south/log/kernel/usb");
+    assert_eq!(Ok(log), Bridge::from_topic_and_payload(topic, &payload));
+}
+
+/// An item that implements `MqttItem` by hand, with only the required methods
+#[derive(Debug, PartialEq)]
+struct Heartbeat(u32);
+
+impl MqttItem for Heartbeat {
+    type DeserializeError = MqttDeserializeError;
+    type SerializeError = MqttSerializeError;
+
+    #[allow(clippy::extra_unused_lifetimes)]
+    fn from_topic_and_payload<'a>(
+        topic: crate::Topic,
+        payload: &[u8],
+    ) -> Result<Self, Self::DeserializeError> {
+        match topic.str() {
+            "heartbeat" => crate::serde_json_deserialize(payload).map(Heartbeat),
+            _ => Err(MqttDeserializeError::UnknownLayer),
+        }
+    }
+
+    fn push_topic_and_payload(
+        self,
+        topic: &mut crate::Topic,
+        payload: &mut Vec<u8>,
+    ) -> Result<(), Self::SerializeError> {
+        topic.push("heartbeat");
+        crate::serde_json_serialize(payload, &self.0)?;
+        Ok(())
+    }
+
+    fn all_generic_topics() -> &'static [&'static str] {
+        &["heartbeat"]
+    }
+}
+
+#[test]
+fn hand_written_items() {
+    let (topic, payload) = Heartbeat(3).into_topic_and_payload().unwrap();
+    assert_eq!(topic.str(), "heartbeat");
+    assert_eq!(
+        Ok(Heartbeat(3)),
+        Heartbeat::from_topic_and_payload(topic, &payload)
+    );
+}
+
+/// Items that only implement `MqttItem` can be nested in derived items, which then can't be
+/// serialized by reference
+#[derive(MqttItem, Debug, PartialEq)]
+#[mqtt_item(topic = "node/<id>/<heartbeat>")]
+struct NodeHeartbeat {
+    id: u32,
+    #[mqtt_item(layer)]
+    heartbeat: Heartbeat,
+}
+
+#[derive(MqttItem, Debug, PartialEq)]
+enum NodeEvent {
+    #[mqtt_item(topic = "node/<1>", extend_into)]
+    Heartbeat(Heartbeat, u32),
+    #[mqtt_item(topic = "node/<0>/reset")]
+    Reset(u32),
+}
+
+#[test]
+fn nested_hand_written_items() {
+    let (topic, payload) = NodeHeartbeat {
+        id: 2,
+        heartbeat: Heartbeat(3),
+    }
+    .into_topic_and_payload()
+    .unwrap();
+    assert_eq!(topic.str(), "node/2/heartbeat");
+    assert_eq!(payload, b"3");
+    assert_eq!(
+        Ok(NodeHeartbeat {
+            id: 2,
+            heartbeat: Heartbeat(3),
+        }),
+        NodeHeartbeat::from_topic_and_payload(topic, &payload)
+    );
+
+    let (topic, payload) = NodeEvent::Heartbeat(Heartbeat(4), 1)
+        .into_topic_and_payload()
+        .unwrap();
+    assert_eq!(topic.str(), "node/1/heartbeat");
+    assert_eq!(payload, b"4");
+    assert_eq!(
+        Ok(NodeEvent::Heartbeat(Heartbeat(4), 1)),
+        NodeEvent::from_topic_and_payload(topic, &payload)
+    );
+
+    let (topic, _) = NodeEvent::Reset(1).into_topic_and_payload().unwrap();
+    assert_eq!(topic.str(), "node/1/reset");
+}
+
+/// Check that writing `item` to writers produces the same topic and payload as
+/// `into_topic_and_payload`
+fn assert_write_matches<T>(item: T)
+where
+    T: MqttItem<SerializeError = MqttSerializeError> + Clone,
+{
+    let (expected_topic, expected_payload) = item.clone().into_topic_and_payload().unwrap();
+
+    let mut topic = crate::Topic::new();
+    let mut buffer = [0; 64];
+    let mut payload = crate::SliceWriter::new(&mut buffer);
+    item.write_topic_and_payload(&mut topic, &mut payload)
+        .unwrap();
+    assert_eq!(expected_topic, topic);
+    assert_eq!(expected_payload, payload.written());
+}
+
+#[test]
+fn writers() {
+    assert_write_matches(MqttUpdate::Variant1 {
+        name: "name1".into(),
+        id: 1,
+        payload: "payload".into(),
+    });
+    assert_write_matches(MqttUpdate::Variant5 {
+        name: "name5".into(),
+        id: Some(5),
+    });
+    assert_write_matches(FormatUpdate::Number(7, 0.5));
+    assert_write_matches(FormatUpdate::Raw(8, vec![1, 2, 3]));
+    assert_write_matches(Site::Gateway {
+        id: 3,
+        msg: DeviceMsg::Temperature(4, 21.5),
+    });
+    assert_write_matches(Climate::Reading {
+        room: "kitchen".into(),
+        temp: 21.5,
+        humidity: 40,
+    });
+    assert_write_matches(Bridge::Log {
+        remote: "south".into(),
+        path: vec!["kernel".into(), "usb".into()],
+        line: "attached".into(),
+    });
+
+    // Invalid layers are rejected, as with `push_topic_and_payload`
+    let update = MqttUpdate::Variant2 {
+        name: "kitchen/+".into(),
+        id: 2,
+        payload: "payload".into(),
+    };
+    assert!(matches!(
+        update.write_topic_and_payload(&mut crate::Topic::new(), &mut Vec::new()),
+        Err(MqttSerializeError::Topic(_))
+    ));
+
+    // A reused topic is restored when the payload does not fit
+    let mut topic = crate::Topic::from_str("prefix");
+    let mut buffer = [0; 4];
+    let update = FormatUpdate::Text {
+        name: "name6".into(),
+        id: 6,
+        payload: "too long".into(),
+    };
+    assert!(matches!(
+        update.write_topic_and_payload(&mut topic, &mut crate::SliceWriter::new(&mut buffer)),
+        Err(MqttSerializeError::CapacityExceeded)
+    ));
+    assert_eq!(topic.str(), "prefix");
+}
+
+/// Check that `item` streams the same topic and payload to an `io::Write` as
+/// `into_topic_and_payload` produces, and that its size hint, if any, is the length of the payload
+#[cfg(feature = "std")]
+fn assert_write_payload_matches<T>(item: T)
+where
+    T: MqttItem<SerializeError = MqttSerializeError> + Clone,
+{
+    let (expected_topic, expected_payload) = item.clone().into_topic_and_payload().unwrap();
+    if let Some(size_hint) = item.payload_size_hint() {
+        assert_eq!(expected_payload.len(), size_hint);
+    }
+
+    let mut payload = Vec::new();
+    let topic = item.write_payload(&mut payload).unwrap();
+    assert_eq!(expected_topic, topic);
+    assert_eq!(expected_payload, payload);
+}
+
+/// An `io::Write` that always fails, like a closed socket
+#[cfg(feature = "std")]
+struct ClosedSocket;
+
+#[cfg(feature = "std")]
+impl std::io::Write for ClosedSocket {
+    fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+        Err(std::io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn write_payload() {
+    assert_write_payload_matches(MqttUpdate::Variant1 {
+        name: "name1".into(),
+        id: 1,
+        payload: "payload".into(),
+    });
+    assert_write_payload_matches(FormatUpdate::Text {
+        name: "name6".into(),
+        id: 6,
+        payload: "text".into(),
+    });
+    assert_write_payload_matches(FormatUpdate::Raw(8, vec![1, 2, 3]));
+    assert_eq!(
+        Some(3),
+        FormatUpdate::Raw(8, vec![1, 2, 3]).payload_size_hint()
+    );
+    assert_write_payload_matches(MqttUpdate::Variant4(4));
+    assert_eq!(Some(0), MqttUpdate::Variant4(4).payload_size_hint());
+    assert_write_payload_matches(Site::Gateway {
+        id: 3,
+        msg: DeviceMsg::Temperature(4, 21.5),
+    });
+    assert_write_payload_matches(Climate::Reading {
+        room: "kitchen".into(),
+        temp: 21.5,
+        humidity: 40,
+    });
+    assert_write_payload_matches(Climate::Limits("kitchen".into(), 18.0, 24.5));
+
+    // Payloads serialized with a `serialize_using` function have no size hint
+    let update = MqttUpdate::Variant5 {
+        name: "name5".into(),
+        id: Some(5),
+    };
+    assert_eq!(None, update.payload_size_hint());
+    let (_, expected_payload) = update.clone().into_topic_and_payload().unwrap();
+    let mut payload = Vec::new();
+    update.write_payload(&mut payload).unwrap();
+    assert_eq!(expected_payload, payload);
+
+    let update = MqttUpdate::Variant1 {
+        name: "name1".into(),
+        id: 1,
+        payload: "payload".into(),
+    };
+    assert!(matches!(
+        update.clone().write_payload(&mut ClosedSocket),
+        Err(MqttSerializeError::Io(_))
+    ));
+
+    // The error of the writer is returned instead of that of the codec
+    let mut buffer = [0; 4];
+    assert!(matches!(
+        update.write_topic_and_payload(
+            &mut crate::Topic::new(),
+            &mut crate::SliceWriter::new(&mut buffer)
+        ),
+        Err(MqttSerializeError::CapacityExceeded)
+    ));
+}
+
+#[cfg(feature = "heapless")]
+#[test]
+fn heapless_writers() {
+    let update = FormatUpdate::Text {
+        name: "name6".into(),
+        id: 6,
+        payload: "text".into(),
+    };
+
+    let mut topic = heapless::String::<32>::new();
+    let mut payload = heapless::Vec::<u8, 8>::new();
+    update
+        .clone()
+        .write_topic_and_payload(&mut topic, &mut payload)
+        .unwrap();
+    assert_eq!(topic.as_str(), "v6/6/name6/text");
+    assert_eq!(payload.as_slice(), b"text");
+
+    let mut topic = heapless::String::<8>::new();
+    assert!(matches!(
+        update.write_topic_and_payload(&mut topic, &mut heapless::Vec::<u8, 8>::new()),
+        Err(MqttSerializeError::CapacityExceeded)
+    ));
+
+    // The layers that fit are removed again when the topic overflows
+    let mut topic = heapless::String::<16>::new();
+    let site = Site::Device(DeviceMsg::Temperature(3, 21.5), 7);
+    assert!(matches!(
+        site.write_topic_and_payload(&mut topic, &mut heapless::Vec::<u8, 8>::new()),
+        Err(MqttSerializeError::CapacityExceeded)
+    ));
+    assert_eq!(topic.as_str(), "");
+}
+
+/// Check that the `serde-json-core` backend produces the same JSON as `serde_json` for `value`,
+/// and parses it into the same value
+#[cfg(all(feature = "serde-json-core", not(feature = "serde_json")))]
+fn assert_json_backends_match<T>(value: T)
+where
+    T: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug,
+{
+    let expected = serde_json::to_vec(&value).unwrap();
+    let mut payload = Vec::new();
+    crate::serde_json_serialize(&mut payload, &value).unwrap();
+    assert_eq!(
+        std::str::from_utf8(&expected).unwrap(),
+        std::str::from_utf8(&payload).unwrap()
+    );
+    assert_eq!(Ok(value), crate::serde_json_deserialize::<T>(&expected));
+}
+
+#[cfg(all(feature = "serde-json-core", not(feature = "serde_json")))]
+#[test]
+fn json_backends() {
+    let updates = [
+        MqttUpdate::Variant1 {
+            name: "name1".into(),
+            id: 1,
+            payload: "quote \" backslash \\ slash / newline \n tab \t".into(),
+        },
+        MqttUpdate::Variant1 {
+            name: "name1".into(),
+            id: 1,
+            payload: "unicode é ✓".into(),
+        },
+        MqttUpdate::Variant3 {
+            name: "".into(),
+            id: 3,
+        },
+    ];
+    for update in updates.iter().cloned() {
+        let (topic, payload) = update.clone().into_topic_and_payload().unwrap();
+        let expected = match &update {
+            MqttUpdate::Variant1 { payload, .. } => serde_json::to_vec(payload).unwrap(),
+            MqttUpdate::Variant3 { name, .. } => serde_json::to_vec(name).unwrap(),
+            _ => unreachable!(),
+        };
+        assert_eq!(expected, payload);
+        assert_eq!(
+            Ok(update),
+            MqttUpdate::from_topic_and_payload(topic, &expected)
+        );
+    }
+
+    assert_json_backends_match(21.5f32);
+    assert_json_backends_match(0.1f64);
+    assert_json_backends_match(1e-7f64);
+    assert_json_backends_match(-7i64);
+    assert_json_backends_match(u64::MAX);
+    assert_json_backends_match(Some(true));
+    assert_json_backends_match(None::<u8>);
+    assert_json_backends_match(vec![1u8, 2, 3]);
+    assert_json_backends_match(String::from("\\u0041"));
+
+    // Control characters and positive exponents are written differently, but parse to the same
+    // value
+    let value = (
+        String::from("control \u{1} \u{b} \u{1f}"),
+        1e21f64,
+        f32::MAX,
+    );
+    let mut payload = Vec::new();
+    crate::serde_json_serialize(&mut payload, &value).unwrap();
+    assert_eq!(
+        std::str::from_utf8(&payload).unwrap(),
+        r#"["control \u0001 \u000B \u001F",1e21,3.4028235e38]"#
+    );
+    assert_eq!(value, serde_json::from_slice(&payload).unwrap());
+    assert_eq!(Ok(value), crate::serde_json_deserialize(&payload));
+
+    let reading = Climate::Reading {
+        room: "kitchen".into(),
+        temp: 21.5,
+        humidity: 40,
+    };
+    let (topic, payload) = reading.clone().into_topic_and_payload().unwrap();
+    assert_eq!(
+        std::str::from_utf8(&payload).unwrap(),
+        r#"{"temp":21.5,"humidity":40}"#
+    );
+    assert_eq!(
+        Ok(reading),
+        Climate::from_topic_and_payload(topic, &payload)
+    );
+}
+
+/// `serde_json` is used if its feature is enabled, even if `serde-json-core` is enabled by another
+/// crate
+#[cfg(feature = "serde_json")]
+#[test]
+fn json_backends_std() {
+    let mut payload = Vec::new();
+    crate::serde_json_serialize(&mut payload, &'x').unwrap();
+    assert_eq!(payload, br#""x""#);
+
+    let map: std::collections::BTreeMap<String, u8> =
+        crate::serde_json_deserialize(br#"{"a":1}"#).unwrap();
+    assert_eq!(map.get("a"), Some(&1));
+
+    assert_eq!(
+        Ok(String::from("\u{1f600}")),
+        crate::serde_json_deserialize(br#""\ud83d\ude00""#)
+    );
+}
+
+#[cfg(all(feature = "serde-json-core", not(feature = "serde_json")))]
+#[test]
+#[should_panic]
+fn json_backends_char() {
+    let _ = crate::serde_json_serialize(&mut Vec::new(), &'x');
+}
+
+#[cfg(all(feature = "serde-json-core", not(feature = "serde_json")))]
+#[test]
+#[should_panic]
+fn json_backends_map() {
+    let _ = crate::serde_json_deserialize::<std::collections::BTreeMap<String, u8>>(br#"{"a":1}"#);
+}
+
+#[cfg(all(feature = "serde-json-core", not(feature = "serde_json")))]
+#[test]
+fn json_backends_surrogate_pairs() {
+    assert!(matches!(
+        crate::serde_json_deserialize::<String>(br#""\ud83d\ude00""#),
+        Err(MqttDeserializeError::SerdeJsonCore(_))
+    ));
+}
+
+/// Counts the heap allocations of each thread, so that tests can check that they don't allocate
+#[cfg(all(feature = "serde-json-core", not(feature = "serde_json")))]
+mod allocations {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    struct CountingAllocator;
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// The number of heap allocations that `f` made on the current thread
+    pub fn count<F: FnOnce()>(f: F) -> usize {
+        let before = ALLOCATIONS.with(Cell::get);
+        f();
+        ALLOCATIONS.with(Cell::get) - before
+    }
+}
+
+#[cfg(all(feature = "serde-json-core", not(feature = "serde_json")))]
+#[test]
+fn json_write_without_allocating() {
+    use crate::{JsonCodec, PayloadCodec, SliceWriter};
+
+    let value = (21.5f64, 1e21f64, String::from("control \u{1f}"));
+    let expected = r#"[21.5,1e21,"control \u001F"]"#;
+
+    assert!(allocations::count(|| drop(vec![0u8; 8])) > 0);
+    let mut buffer = [0; 64];
+    let mut writer = SliceWriter::new(&mut buffer);
+    let allocations = allocations::count(|| JsonCodec::write(&mut writer, &value).unwrap());
+    assert_eq!(allocations, 0);
+    assert_eq!(expected, std::str::from_utf8(writer.written()).unwrap());
+
+    // The payload does not fit
+    let mut buffer = vec![0; expected.len() - 1];
+    let mut writer = SliceWriter::new(&mut buffer);
+    assert!(matches!(
+        JsonCodec::write(&mut writer, &value),
+        Err(MqttSerializeError::CapacityExceeded)
+    ));
+    assert_eq!(writer.written(), b"");
+
+    // `Vec`s grow until the payload fits
+    let mut payload = b"prefix".to_vec();
+    JsonCodec::write(&mut payload, &vec![u64::MAX; 16]).unwrap();
+    assert_eq!(
+        payload[6..],
+        serde_json::to_vec(&vec![u64::MAX; 16]).unwrap()[..]
+    );
+}
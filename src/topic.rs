@@ -1,50 +1,318 @@
-use std::fmt::Display;
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{fmt::Display, iter::FromIterator};
 
-/// A topic tree
+use crate::{push_layer_to, MqttSerializeError, TopicWriter};
+
+/// The maximum length of a topic, in bytes, as defined by the MQTT specification
+pub const MAX_TOPIC_LEN: usize = 65535;
+
+/// An error that occurred while validating a [`Topic`]
 #[derive(Debug, PartialEq, Clone)]
-pub struct TopicTree(Vec<Topic>);
+pub enum TopicError {
+    /// The topic is empty
+    Empty,
+    /// The topic is longer than [`MAX_TOPIC_LEN`] bytes
+    TooLong(usize),
+    /// The topic contains a `+` or `#` wildcard, or a NUL character
+    InvalidCharacter(char),
+    /// A single layer contains a `/`, so pushing it would add multiple layers to the topic
+    LayerSeparator(String),
+    /// A layer of a topic filter contains a wildcard that is not the whole layer, or a `#` wildcard
+    /// that is not the last layer
+    InvalidWildcard(String),
+}
+
+impl Display for TopicError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "topic is empty"),
+            Self::TooLong(len) => write!(
+                f,
+                "topic is {} bytes long, but can be at most {} bytes long",
+                len, MAX_TOPIC_LEN
+            ),
+            Self::InvalidCharacter(c) => {
+                write!(f, "topic contains invalid character `{}`", c.escape_debug())
+            }
+            Self::LayerSeparator(layer) => write!(f, "topic layer `{}` contains a `/`", layer),
+            Self::InvalidWildcard(layer) => {
+                write!(f, "topic filter layer `{}` has an invalid wildcard", layer)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for TopicError {}
+
+/// Check that `layer` is a valid single layer, which is pushed to a topic of `len` bytes
+pub(crate) fn validate_layer(len: usize, layer: &str) -> Result<(), TopicError> {
+    if layer.contains('/') {
+        return Err(TopicError::LayerSeparator(layer.to_string()));
+    }
+    validate_characters(layer)?;
+
+    let len = if len == 0 {
+        layer.len()
+    } else {
+        len + 1 + layer.len()
+    };
+    if len > MAX_TOPIC_LEN {
+        return Err(TopicError::TooLong(len));
+    }
+    Ok(())
+}
+
+/// Check that `input` contains no wildcards or NUL characters
+fn validate_characters(input: &str) -> Result<(), TopicError> {
+    match input.chars().find(|c| matches!(c, '+' | '#' | '\0')) {
+        Some(c) => Err(TopicError::InvalidCharacter(c)),
+        None => Ok(()),
+    }
+}
+
+/// A tree of topics, in which every node is a single layer
+///
+/// The layers of a tree are kept in the order in which they were first inserted. A tree is usually
+/// built from the generic topics of an [`MqttItem`](crate::MqttItem), with [`TopicTree::from_item`],
+/// so the [`Display`] implementation renders the topic namespace of that item as an indented tree.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct TopicTree {
+    /// The layers below this one
+    children: Vec<(String, TopicTree)>,
+    /// Whether a topic ends at this layer
+    end: bool,
+}
 
 impl TopicTree {
+    /// Create a new empty tree
     pub fn new() -> Self {
-        Self(Vec::new())
+        Self {
+            children: Vec::new(),
+            end: false,
+        }
+    }
+
+    /// Create a tree of all generic topics of `M`
+    pub fn from_item<M: crate::MqttItem>() -> Self {
+        M::all_generic_topics().iter().copied().collect()
+    }
+
+    /// Insert `topic` into this tree, returning `false` if it was already present
+    pub fn insert(&mut self, topic: &str) -> bool {
+        let mut node = self;
+        for layer in topic.split('/') {
+            node = node.child_or_insert(layer);
+        }
+        !core::mem::replace(&mut node.end, true)
+    }
+
+    /// Whether `topic` is present in this tree
+    pub fn contains(&self, topic: &str) -> bool {
+        self.get(topic).is_some_and(|node| node.end)
+    }
+
+    /// Get the subtree of all layers below `prefix`
+    pub fn get(&self, prefix: &str) -> Option<&TopicTree> {
+        prefix
+            .split('/')
+            .try_fold(self, |node, layer| node.child(layer))
     }
 
-    pub fn inner(&self) -> &Vec<Topic> {
-        &self.0
+    /// Iterate over the layers directly below the root of this tree, and their subtrees
+    pub fn layers(&self) -> impl Iterator<Item = (&str, &TopicTree)> {
+        self.children
+            .iter()
+            .map(|(layer, child)| (layer.as_str(), child))
     }
 
-    pub fn inner_mut(&mut self) -> &mut Vec<Topic> {
-        &mut self.0
+    /// The amount of topics in this tree
+    pub fn len(&self) -> usize {
+        self.children
+            .iter()
+            .map(|(_, child)| child.len())
+            .sum::<usize>()
+            + self.end as usize
+    }
+
+    /// Whether this tree contains no topics
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterate over all topics in this tree
+    pub fn iter(&self) -> impl Iterator<Item = String> {
+        let mut topics = Vec::new();
+        for (layer, child) in &self.children {
+            child.collect(layer.clone(), &mut topics);
+        }
+        topics.into_iter()
+    }
+
+    /// Iterate over all topics in this tree that start with the layers of `prefix`
+    pub fn iter_prefix(&self, prefix: &str) -> impl Iterator<Item = String> {
+        let mut topics = Vec::new();
+        if let Some(node) = self.get(prefix) {
+            node.collect(prefix.to_string(), &mut topics);
+        }
+        topics.into_iter()
+    }
+
+    /// Iterate over all topics in this tree that are matched by `filter`
+    ///
+    /// The layers of the topics in this tree are compared literally, so a `+` layer in a topic is
+    /// only matched by a `+` or `#` wildcard in `filter`.
+    pub fn query(&self, filter: &TopicFilter) -> impl Iterator<Item = String> {
+        let mut topics = Vec::new();
+        let mut layers = filter.layers();
+        // Wildcards in the first layer of a filter don't match topics that start with `$`
+        let children = self
+            .children
+            .iter()
+            .filter(|(layer, _)| !layer.starts_with('$'));
+        match layers.next() {
+            Some("#") => {
+                children.for_each(|(layer, child)| child.collect(layer.clone(), &mut topics))
+            }
+            Some("+") => children.for_each(|(layer, child)| {
+                child.query_into(layer.clone(), layers.clone(), &mut topics)
+            }),
+            Some(layer) => {
+                if let Some(child) = self.child(layer) {
+                    child.query_into(layer.to_string(), layers, &mut topics)
+                }
+            }
+            None => {}
+        }
+        topics.into_iter()
+    }
+
+    fn child(&self, layer: &str) -> Option<&TopicTree> {
+        self.children
+            .iter()
+            .find(|(child_layer, _)| child_layer == layer)
+            .map(|(_, child)| child)
+    }
+
+    fn child_or_insert(&mut self, layer: &str) -> &mut TopicTree {
+        let index = match self
+            .children
+            .iter()
+            .position(|(child_layer, _)| child_layer == layer)
+        {
+            Some(index) => index,
+            None => {
+                self.children.push((layer.to_string(), TopicTree::new()));
+                self.children.len() - 1
+            }
+        };
+        &mut self.children[index].1
+    }
+
+    /// Collect all topics in this tree, where `topic` is the topic of the root of this tree
+    fn collect(&self, topic: String, topics: &mut Vec<String>) {
+        if self.end {
+            topics.push(topic.clone());
+        }
+        for (layer, child) in &self.children {
+            child.collect(format!("{}/{}", topic, layer), topics);
+        }
+    }
+
+    /// Collect all topics in this tree that are matched by the remaining `layers` of a filter, where
+    /// `topic` is the topic of the root of this tree
+    fn query_into<'a>(
+        &self,
+        topic: String,
+        mut layers: impl Iterator<Item = &'a str> + Clone,
+        topics: &mut Vec<String>,
+    ) {
+        match layers.next() {
+            None if self.end => topics.push(topic),
+            None => {}
+            Some("#") => self.collect(topic, topics),
+            Some("+") => {
+                for (layer, child) in &self.children {
+                    child.query_into(format!("{}/{}", topic, layer), layers.clone(), topics);
+                }
+            }
+            Some(layer) => {
+                if let Some(child) = self.child(layer) {
+                    child.query_into(format!("{}/{}", topic, layer), layers, topics);
+                }
+            }
+        }
+    }
+
+    fn fmt_layers(&self, f: &mut core::fmt::Formatter<'_>, depth: usize) -> core::fmt::Result {
+        for (layer, child) in &self.children {
+            writeln!(f, "{:indent$}{}", "", layer, indent = depth * 2)?;
+            child.fmt_layers(f, depth + 1)?;
+        }
+        Ok(())
     }
 }
 
-impl Display for TopicTree {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut collected = String::new();
-        for topic in self.inner() {
-            collected.push_str(&format!("{}\n", topic));
+impl<'a> FromIterator<&'a str> for TopicTree {
+    fn from_iter<I: IntoIterator<Item = &'a str>>(iter: I) -> Self {
+        let mut tree = TopicTree::new();
+        tree.extend(iter);
+        tree
+    }
+}
+
+impl<'a> Extend<&'a str> for TopicTree {
+    fn extend<I: IntoIterator<Item = &'a str>>(&mut self, iter: I) {
+        for topic in iter {
+            self.insert(topic);
         }
-        f.write_str(&collected)
+    }
+}
+
+impl Display for TopicTree {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.fmt_layers(f, 0)
     }
 }
 
 /// An MQTT topic
 ///
 /// A topic is defined as layers, separated by a `/` character.
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Default)]
 pub struct Topic {
     inner: String,
 }
 
 impl Topic {
     /// Create a new topic from the given string
+    ///
+    /// The topic is not validated, see [`Topic::try_from_str`] for a fallible alternative.
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(input: &str) -> Self {
-        // TODO: topic verification?
         Self {
             inner: input.to_string(),
         }
     }
 
+    /// Create a new topic from the given string, checking that it is a valid MQTT topic name
+    ///
+    /// A valid topic name is not empty, at most [`MAX_TOPIC_LEN`] bytes long, and contains no
+    /// wildcards (`+` and `#`) or NUL characters.
+    pub fn try_from_str(input: &str) -> Result<Self, TopicError> {
+        if input.is_empty() {
+            return Err(TopicError::Empty);
+        }
+        if input.len() > MAX_TOPIC_LEN {
+            return Err(TopicError::TooLong(input.len()));
+        }
+        validate_characters(input)?;
+        Ok(Self::from_str(input))
+    }
+
     /// Create a new empty topic
     pub fn new() -> Self {
         Self {
@@ -58,12 +326,21 @@ impl Topic {
     /// as if multiple layers (as separated by each `/` character) have been pushed
     /// to the topic
     pub fn push(&mut self, layer: &str) {
-        // TODO: topic verification?
-        let value = &mut self.inner;
-        if value.len() != 0 {
+        if !self.inner.is_empty() {
             self.inner.push('/');
         }
-        self.inner.push_str(&layer)
+        self.inner.push_str(layer)
+    }
+
+    /// Push a single new layer to this topic, checking that it is a valid layer
+    ///
+    /// Unlike [`Topic::push`], `layer` may not contain a `/` character. It may also not contain
+    /// wildcards (`+` and `#`) or NUL characters, and the resulting topic may not be longer than
+    /// [`MAX_TOPIC_LEN`] bytes. If `layer` is invalid, the topic is left unchanged.
+    pub fn try_push(&mut self, layer: &str) -> Result<(), TopicError> {
+        validate_layer(self.inner.len(), layer)?;
+        self.push(layer);
+        Ok(())
     }
 
     /// Push a new layer to the front of this topic
@@ -73,7 +350,7 @@ impl Topic {
     /// by those `/` characters, were prepended to the topic.
     pub fn push_front(&mut self, layer: &str) {
         let mut value = self.inner.clone();
-        if value.len() != 0 {
+        if !value.is_empty() {
             value = layer.to_string() + "/" + &value;
         } else {
             value = layer.to_string();
@@ -82,7 +359,7 @@ impl Topic {
     }
 
     /// Create an iterator over the layers of this topic
-    pub fn layers(&self) -> impl Iterator<Item = &str> {
+    pub fn layers(&self) -> impl Iterator<Item = &str> + Clone {
         self.inner.split('/')
     }
 
@@ -93,20 +370,245 @@ impl Topic {
 }
 
 impl Display for Topic {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.write_str(self.str())
     }
 }
 
+impl TopicWriter for Topic {
+    fn push_layer<L>(&mut self, layer: &L) -> Result<(), MqttSerializeError>
+    where
+        L: Display + ?Sized,
+    {
+        push_layer_to(&mut self.inner, layer)
+    }
+
+    fn topic_len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn truncate_topic(&mut self, len: usize) {
+        self.inner.truncate(len)
+    }
+}
+
 impl From<Topic> for String {
     fn from(input: Topic) -> String {
         input.str().to_string()
     }
 }
 
+/// An MQTT topic filter
+///
+/// A topic filter is a [`Topic`] whose layers can also be the single-layer wildcard `+`, or the
+/// multi-layer wildcard `#` as the last layer.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct TopicFilter {
+    inner: String,
+}
+
+impl TopicFilter {
+    /// Create a new topic filter from the given string, checking that it is a valid MQTT topic filter
+    pub fn try_from_str(input: &str) -> Result<Self, TopicError> {
+        if input.is_empty() {
+            return Err(TopicError::Empty);
+        }
+        if input.len() > MAX_TOPIC_LEN {
+            return Err(TopicError::TooLong(input.len()));
+        }
+        if input.contains('\0') {
+            return Err(TopicError::InvalidCharacter('\0'));
+        }
+
+        let mut layers = input.split('/').peekable();
+        while let Some(layer) = layers.next() {
+            let valid = match layer {
+                "+" => true,
+                "#" => layers.peek().is_none(),
+                layer => !layer.contains(['+', '#']),
+            };
+            if !valid {
+                return Err(TopicError::InvalidWildcard(layer.to_string()));
+            }
+        }
+
+        Ok(Self {
+            inner: input.to_string(),
+        })
+    }
+
+    /// Create an iterator over the layers of this topic filter
+    pub fn layers(&self) -> impl Iterator<Item = &str> + Clone {
+        self.inner.split('/')
+    }
+
+    /// Get the raw underlying `str` representing this topic filter
+    pub fn str(&self) -> &str {
+        &self.inner
+    }
+
+    /// Whether `topic` matches this topic filter
+    ///
+    /// As defined by the MQTT specification, topics that start with `$` are not matched by filters
+    /// that start with a wildcard.
+    pub fn matches(&self, topic: &Topic) -> bool {
+        if topic.str().starts_with('$') && self.starts_with_wildcard() {
+            return false;
+        }
+
+        let mut topic = topic.layers();
+        for layer in self.layers() {
+            match (layer, topic.next()) {
+                ("#", _) => return true,
+                ("+", Some(_)) => {}
+                (layer, Some(topic_layer)) if layer == topic_layer => {}
+                _ => return false,
+            }
+        }
+        topic.next().is_none()
+    }
+
+    /// Whether there is any topic that is matched by both this topic filter and `other`
+    pub fn overlaps(&self, other: &TopicFilter) -> bool {
+        if self.excludes_system_topics_of(other) || other.excludes_system_topics_of(self) {
+            return false;
+        }
+
+        let (mut layers, mut other_layers) = (self.layers(), other.layers());
+        loop {
+            match (layers.next(), other_layers.next()) {
+                (Some("#"), _) | (_, Some("#")) => return true,
+                (None, None) => return true,
+                (Some("+"), Some(_)) | (Some(_), Some("+")) => {}
+                (Some(layer), Some(other_layer)) if layer == other_layer => {}
+                _ => return false,
+            }
+        }
+    }
+
+    /// Whether every topic that is matched by `other` is also matched by this topic filter
+    ///
+    /// If so, a subscription to `other` is redundant next to a subscription to this topic filter.
+    pub fn subsumes(&self, other: &TopicFilter) -> bool {
+        if self.excludes_system_topics_of(other) {
+            return false;
+        }
+
+        let (mut layers, mut other_layers) = (self.layers(), other.layers());
+        loop {
+            match (layers.next(), other_layers.next()) {
+                (Some("#"), _) => return true,
+                (None, None) => return true,
+                (Some("+"), Some(other_layer)) if other_layer != "#" => {}
+                (Some(layer), Some(other_layer)) if layer == other_layer => {}
+                _ => return false,
+            }
+        }
+    }
+
+    fn starts_with_wildcard(&self) -> bool {
+        matches!(self.layers().next(), Some("+" | "#"))
+    }
+
+    /// Whether `other` only matches topics that start with `$`, which this topic filter can
+    /// never match because it starts with a wildcard
+    fn excludes_system_topics_of(&self, other: &TopicFilter) -> bool {
+        self.starts_with_wildcard() && other.str().starts_with('$')
+    }
+}
+
+impl Display for TopicFilter {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.str())
+    }
+}
+
+impl From<TopicFilter> for String {
+    fn from(input: TopicFilter) -> String {
+        input.inner
+    }
+}
+
+/// A value that holds all remaining layers of a topic, as captured by a trailing
+/// `<field..>` layer in the topic of an [`MqttItem`](crate::MqttItem)
+///
+/// The lifetime `'a` is that of the layers that the value is collected from, so that
+/// [`MqttItemRef`](crate::MqttItemRef) items can borrow them, as `Vec<&'a str>`.
+pub trait TopicRest<'a>: Sized {
+    /// Push the layers held by this value to `topic`, checking that each of them is a valid layer
+    fn push_layers(&self, topic: &mut Topic) -> Result<(), TopicError>;
+
+    /// Push the layers held by this value to `topic`, checking that each of them is a valid layer
+    fn write_layers<W>(&self, topic: &mut W) -> Result<(), MqttSerializeError>
+    where
+        W: TopicWriter + ?Sized;
+
+    /// Collect all remaining `layers` into a value
+    fn from_layers(layers: impl Iterator<Item = &'a str>) -> Self;
+}
+
+impl<'a> TopicRest<'a> for Vec<String> {
+    fn push_layers(&self, topic: &mut Topic) -> Result<(), TopicError> {
+        self.iter().try_for_each(|layer| topic.try_push(layer))
+    }
+
+    fn write_layers<W>(&self, topic: &mut W) -> Result<(), MqttSerializeError>
+    where
+        W: TopicWriter + ?Sized,
+    {
+        self.iter().try_for_each(|layer| topic.push_layer(layer))
+    }
+
+    fn from_layers(layers: impl Iterator<Item = &'a str>) -> Self {
+        layers.map(ToString::to_string).collect()
+    }
+}
+
+impl<'a> TopicRest<'a> for Vec<&'a str> {
+    fn push_layers(&self, topic: &mut Topic) -> Result<(), TopicError> {
+        self.iter().try_for_each(|layer| topic.try_push(layer))
+    }
+
+    fn write_layers<W>(&self, topic: &mut W) -> Result<(), MqttSerializeError>
+    where
+        W: TopicWriter + ?Sized,
+    {
+        self.iter().try_for_each(|layer| topic.push_layer(*layer))
+    }
+
+    fn from_layers(layers: impl Iterator<Item = &'a str>) -> Self {
+        layers.collect()
+    }
+}
+
+impl<'a> TopicRest<'a> for Topic {
+    fn push_layers(&self, topic: &mut Topic) -> Result<(), TopicError> {
+        if self.inner.is_empty() {
+            return Ok(());
+        }
+        self.layers().try_for_each(|layer| topic.try_push(layer))
+    }
+
+    fn write_layers<W>(&self, topic: &mut W) -> Result<(), MqttSerializeError>
+    where
+        W: TopicWriter + ?Sized,
+    {
+        if self.inner.is_empty() {
+            return Ok(());
+        }
+        self.layers().try_for_each(|layer| topic.push_layer(layer))
+    }
+
+    fn from_layers(layers: impl Iterator<Item = &'a str>) -> Self {
+        let mut topic = Topic::new();
+        layers.for_each(|layer| topic.push(layer));
+        topic
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Topic;
+    use super::{Topic, TopicError, TopicFilter, TopicTree, MAX_TOPIC_LEN};
 
     #[test]
     fn layer_count() {
@@ -138,4 +640,221 @@ mod tests {
         topic.push_front("prefix");
         assert_eq!("prefix/region", topic.str());
     }
+
+    #[test]
+    fn try_from_str() {
+        assert_eq!(
+            Topic::try_from_str("region/device/1234").unwrap().str(),
+            "region/device/1234"
+        );
+        assert_eq!(Topic::try_from_str(""), Err(TopicError::Empty));
+        assert_eq!(
+            Topic::try_from_str("region/+/1234"),
+            Err(TopicError::InvalidCharacter('+'))
+        );
+        assert_eq!(
+            Topic::try_from_str("region/#"),
+            Err(TopicError::InvalidCharacter('#'))
+        );
+        assert_eq!(
+            Topic::try_from_str("region/\0"),
+            Err(TopicError::InvalidCharacter('\0'))
+        );
+        assert_eq!(
+            Topic::try_from_str(&"a".repeat(MAX_TOPIC_LEN + 1)),
+            Err(TopicError::TooLong(MAX_TOPIC_LEN + 1))
+        );
+    }
+
+    #[test]
+    fn try_push() {
+        let mut topic = Topic::from_str("region");
+        topic.try_push("subtopic").unwrap();
+        assert_eq!("region/subtopic", topic.str());
+
+        assert_eq!(
+            topic.try_push("a/b"),
+            Err(TopicError::LayerSeparator("a/b".into()))
+        );
+        assert_eq!(topic.try_push("#"), Err(TopicError::InvalidCharacter('#')));
+        assert_eq!(
+            topic.try_push(&"a".repeat(MAX_TOPIC_LEN)),
+            Err(TopicError::TooLong(
+                MAX_TOPIC_LEN + "region/subtopic/".len()
+            ))
+        );
+        assert_eq!("region/subtopic", topic.str());
+    }
+
+    fn filter(input: &str) -> TopicFilter {
+        TopicFilter::try_from_str(input).unwrap()
+    }
+
+    #[test]
+    fn filter_validation() {
+        for valid in ["#", "+", "sport/#", "sport/+/player1", "+/+/#", "/finance"] {
+            assert_eq!(filter(valid).str(), valid);
+        }
+
+        assert_eq!(TopicFilter::try_from_str(""), Err(TopicError::Empty));
+        assert_eq!(
+            TopicFilter::try_from_str("sport/tennis#"),
+            Err(TopicError::InvalidWildcard("tennis#".into()))
+        );
+        assert_eq!(
+            TopicFilter::try_from_str("sport/#/ranking"),
+            Err(TopicError::InvalidWildcard("#".into()))
+        );
+        assert_eq!(
+            TopicFilter::try_from_str("sport+"),
+            Err(TopicError::InvalidWildcard("sport+".into()))
+        );
+    }
+
+    #[test]
+    fn filter_matches() {
+        let matches = |f: &str, t: &str| filter(f).matches(&Topic::from_str(t));
+
+        assert!(matches("sport/tennis/player1/#", "sport/tennis/player1"));
+        assert!(matches(
+            "sport/tennis/player1/#",
+            "sport/tennis/player1/ranking"
+        ));
+        assert!(matches("sport/#", "sport"));
+        assert!(matches("#", "sport/tennis"));
+        assert!(!matches("sport/tennis/#", "sport/tennis2"));
+
+        assert!(matches("sport/tennis/+", "sport/tennis/player1"));
+        assert!(!matches("sport/tennis/+", "sport/tennis/player1/ranking"));
+        assert!(!matches("sport/+", "sport"));
+        assert!(matches("sport/+", "sport/"));
+        assert!(matches("+/+", "/finance"));
+        assert!(matches("/+", "/finance"));
+        assert!(!matches("+", "/finance"));
+
+        assert!(!matches("#", "$SYS/uptime"));
+        assert!(!matches("+/uptime", "$SYS/uptime"));
+        assert!(matches("$SYS/#", "$SYS/uptime"));
+        assert!(matches("$SYS/+", "$SYS/uptime"));
+    }
+
+    #[test]
+    fn filter_overlaps() {
+        let overlaps = |a: &str, b: &str| {
+            let (a, b) = (filter(a), filter(b));
+            assert_eq!(a.overlaps(&b), b.overlaps(&a));
+            a.overlaps(&b)
+        };
+
+        assert!(overlaps("sport/+/player1", "sport/tennis/+"));
+        assert!(overlaps("sport/#", "sport"));
+        assert!(overlaps("+/+", "sport/#"));
+        assert!(overlaps("#", "+"));
+        assert!(!overlaps("sport/+", "sport/+/player1"));
+        assert!(!overlaps("sport/tennis", "sport/golf"));
+        assert!(!overlaps("#", "$SYS/#"));
+        assert!(overlaps("$SYS/+", "$SYS/#"));
+    }
+
+    #[test]
+    fn filter_subsumes() {
+        let subsumes = |a: &str, b: &str| filter(a).subsumes(&filter(b));
+
+        assert!(subsumes("#", "sport/+/player1"));
+        assert!(subsumes("sport/#", "sport"));
+        assert!(subsumes("sport/#", "sport/tennis/#"));
+        assert!(subsumes("sport/+/player1", "sport/tennis/player1"));
+        assert!(subsumes("sport/+", "sport/+"));
+        assert!(!subsumes("sport/+", "sport/#"));
+        assert!(!subsumes("sport/tennis/player1", "sport/+/player1"));
+        assert!(!subsumes("sport/+", "sport"));
+        assert!(!subsumes("#", "$SYS/#"));
+        assert!(subsumes("$SYS/#", "$SYS/+"));
+    }
+
+    fn tree() -> TopicTree {
+        [
+            "device/+/temp",
+            "device/+/online",
+            "device/+/config/#",
+            "site/+/device/+/temp",
+            "$SYS/broker/uptime",
+        ]
+        .iter()
+        .copied()
+        .collect()
+    }
+
+    #[test]
+    fn tree_insert() {
+        let mut tree = tree();
+        assert_eq!(tree.len(), 5);
+        assert!(tree.contains("device/+/temp"));
+        assert!(!tree.contains("device/+"));
+        assert!(!tree.contains("device/+/humidity"));
+
+        assert!(tree.insert("device/+"));
+        assert!(!tree.insert("device/+"));
+        assert!(tree.contains("device/+"));
+        assert_eq!(tree.len(), 6);
+
+        let device = tree.get("device/+").unwrap();
+        assert_eq!(
+            device.layers().map(|(layer, _)| layer).collect::<Vec<_>>(),
+            ["temp", "online", "config"]
+        );
+        assert!(tree.get("device/1").is_none());
+        assert!(TopicTree::new().is_empty());
+    }
+
+    #[test]
+    fn tree_iter() {
+        let tree = tree();
+        assert_eq!(
+            tree.iter().collect::<Vec<_>>(),
+            [
+                "device/+/temp",
+                "device/+/online",
+                "device/+/config/#",
+                "site/+/device/+/temp",
+                "$SYS/broker/uptime",
+            ]
+        );
+        assert_eq!(
+            tree.iter_prefix("device/+/config").collect::<Vec<_>>(),
+            ["device/+/config/#"]
+        );
+        assert_eq!(
+            tree.iter_prefix("site").collect::<Vec<_>>(),
+            ["site/+/device/+/temp"]
+        );
+        assert_eq!(tree.iter_prefix("other").count(), 0);
+    }
+
+    #[test]
+    fn tree_query() {
+        let tree = tree();
+        let query = |filter: &str| {
+            tree.query(&TopicFilter::try_from_str(filter).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(query("device/+/temp"), ["device/+/temp"]);
+        assert_eq!(query("+/+/temp"), ["device/+/temp"]);
+        assert_eq!(
+            query("device/#"),
+            ["device/+/temp", "device/+/online", "device/+/config/#"]
+        );
+        assert_eq!(query("#").len(), 4);
+        assert_eq!(query("$SYS/#"), ["$SYS/broker/uptime"]);
+        assert!(query("device/1/temp").is_empty());
+    }
+
+    #[test]
+    fn tree_display() {
+        assert_eq!(
+            tree().to_string(),
+            "device\n  +\n    temp\n    online\n    config\n      #\nsite\n  +\n    device\n      +\n        temp\n$SYS\n  broker\n    uptime\n"
+        );
+    }
 }
@@ -0,0 +1,446 @@
+use alloc::{string::String, vec::Vec};
+use core::fmt::{Display, Write};
+
+use crate::{validate_layer, MqttSerializeError};
+
+/// A buffer that the topic of an [`MqttItem`](crate::MqttItem) can be written to, one layer at a
+/// time
+///
+/// Unlike [`Topic`](crate::Topic), a writer can have a fixed capacity, such as
+/// `heapless::String<N>` (with the `heapless` feature).
+pub trait TopicWriter {
+    /// Push `layer`, as formatted by its [`Display`] implementation, as a single new layer of the
+    /// topic
+    ///
+    /// The layer is checked like [`Topic::try_push`](crate::Topic::try_push). If `layer` is
+    /// invalid or does not fit in the writer, the topic is left unchanged.
+    fn push_layer<L>(&mut self, layer: &L) -> Result<(), MqttSerializeError>
+    where
+        L: Display + ?Sized;
+
+    /// The length of the topic that has been written so far, in bytes
+    fn topic_len(&self) -> usize;
+
+    /// Remove the layers that were pushed after the topic was `len` bytes long
+    fn truncate_topic(&mut self, len: usize);
+}
+
+/// Call `write` with `topic`, restoring `topic` to its previous contents if `write` fails
+///
+/// This is used to write the topic of an item, so that a reused writer does not keep part of the
+/// topic of an item that could not be written completely.
+#[doc(hidden)]
+pub fn write_or_restore_topic<T, E, F>(topic: &mut T, write: F) -> Result<(), E>
+where
+    T: TopicWriter + ?Sized,
+    F: FnOnce(&mut T) -> Result<(), E>,
+{
+    let len = topic.topic_len();
+    let result = write(topic);
+    if result.is_err() {
+        topic.truncate_topic(len);
+    }
+    result
+}
+
+/// A buffer that the payload of an [`MqttItem`](crate::MqttItem) can be written to
+///
+/// Unlike `Vec<u8>`, a writer can have a fixed capacity, such as a [`SliceWriter`] or
+/// `heapless::Vec<u8, N>` (with the `heapless` feature).
+pub trait PayloadWriter {
+    /// Append `bytes` to the payload
+    ///
+    /// If `bytes` do not fit in the writer, [`MqttSerializeError::CapacityExceeded`] is returned
+    /// and the payload is left unchanged.
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), MqttSerializeError>;
+
+    /// Let `write` append bytes to the payload by writing them directly into the spare capacity
+    /// of this writer
+    ///
+    /// `write` is given the spare capacity, and returns the number of bytes that it wrote to its
+    /// start, which are then committed to the payload. If it returns an error, nothing is
+    /// committed. Writers that can grow, such as `Vec<u8>`, may call `write` again with more
+    /// capacity if it returns [`MqttSerializeError::CapacityExceeded`].
+    ///
+    /// This lets serializers that write to a byte slice, such as `serde_json_core`, write the
+    /// payload without an intermediate buffer. Returns `None`, without calling `write`, if the
+    /// writer has no spare capacity to write to, such as an `IoWriter`. The default
+    /// implementation always does.
+    fn write_in_place(
+        &mut self,
+        write: &mut dyn FnMut(&mut [u8]) -> Result<usize, MqttSerializeError>,
+    ) -> Option<Result<(), MqttSerializeError>> {
+        let _ = write;
+        None
+    }
+}
+
+/// A string buffer that the layers of a topic are formatted into
+pub(crate) trait TopicBuffer: Write {
+    fn as_str(&self) -> &str;
+
+    fn truncate(&mut self, len: usize);
+}
+
+impl TopicBuffer for String {
+    fn as_str(&self) -> &str {
+        self
+    }
+
+    fn truncate(&mut self, len: usize) {
+        String::truncate(self, len)
+    }
+}
+
+/// Format `layer` into `buffer` as a new layer, and check that it is valid
+///
+/// If it is not, `buffer` is restored to its previous contents.
+pub(crate) fn push_layer_to<B, L>(buffer: &mut B, layer: &L) -> Result<(), MqttSerializeError>
+where
+    B: TopicBuffer + ?Sized,
+    L: Display + ?Sized,
+{
+    let len = buffer.as_str().len();
+    let result = if len == 0 {
+        write!(buffer, "{}", layer)
+    } else {
+        write!(buffer, "/{}", layer)
+    };
+    let result = match result {
+        Ok(()) => {
+            let start = if len == 0 { 0 } else { len + 1 };
+            validate_layer(len, &buffer.as_str()[start..]).map_err(MqttSerializeError::Topic)
+        }
+        Err(_) => Err(MqttSerializeError::CapacityExceeded),
+    };
+    if result.is_err() {
+        buffer.truncate(len);
+    }
+    result
+}
+
+impl PayloadWriter for Vec<u8> {
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), MqttSerializeError> {
+        self.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write_in_place(
+        &mut self,
+        write: &mut dyn FnMut(&mut [u8]) -> Result<usize, MqttSerializeError>,
+    ) -> Option<Result<(), MqttSerializeError>> {
+        // The spare capacity is doubled until the bytes fit
+        let start = self.len();
+        let mut len = 64;
+        loop {
+            self.resize(start + len, 0);
+            match write(&mut self[start..]) {
+                Ok(written) => {
+                    self.truncate(start + written);
+                    return Some(Ok(()));
+                }
+                Err(MqttSerializeError::CapacityExceeded) => len *= 2,
+                Err(e) => {
+                    self.truncate(start);
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// A [`PayloadWriter`] that writes the payload into a fixed byte slice
+pub struct SliceWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> SliceWriter<'a> {
+    /// Create a new writer that writes to the start of `buffer`
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self { buffer, len: 0 }
+    }
+
+    /// The bytes that have been written so far
+    pub fn written(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    /// Consume this writer, returning the bytes that have been written
+    pub fn into_written(self) -> &'a mut [u8] {
+        &mut self.buffer[..self.len]
+    }
+}
+
+impl PayloadWriter for SliceWriter<'_> {
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), MqttSerializeError> {
+        let end = self.len + bytes.len();
+        if end > self.buffer.len() {
+            return Err(MqttSerializeError::CapacityExceeded);
+        }
+        self.buffer[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    fn write_in_place(
+        &mut self,
+        write: &mut dyn FnMut(&mut [u8]) -> Result<usize, MqttSerializeError>,
+    ) -> Option<Result<(), MqttSerializeError>> {
+        Some(write(&mut self.buffer[self.len..]).map(|written| self.len += written))
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl<const N: usize> TopicBuffer for heapless::String<N> {
+    fn as_str(&self) -> &str {
+        self
+    }
+
+    fn truncate(&mut self, len: usize) {
+        heapless::String::truncate(self, len)
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl<const N: usize> TopicWriter for heapless::String<N> {
+    fn push_layer<L>(&mut self, layer: &L) -> Result<(), MqttSerializeError>
+    where
+        L: Display + ?Sized,
+    {
+        push_layer_to(self, layer)
+    }
+
+    fn topic_len(&self) -> usize {
+        self.len()
+    }
+
+    fn truncate_topic(&mut self, len: usize) {
+        self.truncate(len)
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl<const N: usize> PayloadWriter for heapless::Vec<u8, N> {
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), MqttSerializeError> {
+        self.extend_from_slice(bytes)
+            .map_err(|_| MqttSerializeError::CapacityExceeded)
+    }
+
+    fn write_in_place(
+        &mut self,
+        write: &mut dyn FnMut(&mut [u8]) -> Result<usize, MqttSerializeError>,
+    ) -> Option<Result<(), MqttSerializeError>> {
+        let start = self.len();
+        // Can't fail, as the length is the capacity
+        let _ = self.resize(N, 0);
+        let result = write(&mut self[start..]);
+        self.truncate(start + *result.as_ref().unwrap_or(&0));
+        Some(result.map(|_| ()))
+    }
+}
+
+/// A [`PayloadWriter`] that writes the payload to an [`std::io::Write`], such as a socket
+///
+/// Codecs that support it stream the payload to the writer, without serializing it into a buffer
+/// first. All built-in codecs do, but payloads that are serialized with a `serialize_using`
+/// function are serialized into a `Vec<u8>` first.
+#[cfg(feature = "std")]
+pub struct IoWriter<W: ?Sized> {
+    writer: W,
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write> IoWriter<W> {
+    /// Create a new writer that writes to `writer`
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Consume this writer, returning the underlying writer
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write + ?Sized> PayloadWriter for IoWriter<W> {
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), MqttSerializeError> {
+        self.writer.write_all(bytes).map_err(MqttSerializeError::Io)
+    }
+}
+
+/// Adapts a [`PayloadWriter`] to [`std::io::Write`], keeping the error of the writer, so that
+/// serializers that write to an [`std::io::Write`] can stream to a [`PayloadWriter`]
+///
+/// It is only used by the codecs that stream their payload: `serde_json`, CBOR and MessagePack.
+#[cfg(feature = "std")]
+pub(crate) struct PayloadIo<'a, W: ?Sized> {
+    writer: &'a mut W,
+    error: Option<MqttSerializeError>,
+}
+
+#[cfg(feature = "std")]
+impl<'a, W: PayloadWriter + ?Sized> PayloadIo<'a, W> {
+    pub(crate) fn new(writer: &'a mut W) -> Self {
+        Self {
+            writer,
+            error: None,
+        }
+    }
+
+    /// Convert the `error` of the serializer, returning the error of the writer instead if the
+    /// serializer failed because of it
+    pub(crate) fn error<E>(self, error: E) -> MqttSerializeError
+    where
+        E: Into<MqttSerializeError>,
+    {
+        self.error.unwrap_or_else(|| error.into())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: PayloadWriter + ?Sized> std::io::Write for PayloadIo<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.writer.write_bytes(buf).map_err(|e| {
+            self.error = Some(e);
+            std::io::Error::other("the payload writer failed")
+        })
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Adapts a [`PayloadWriter`] to [`core::fmt::Write`], keeping the error of the writer
+pub(crate) struct FmtWriter<'a, W: ?Sized> {
+    writer: &'a mut W,
+    error: Option<MqttSerializeError>,
+}
+
+impl<'a, W: PayloadWriter + ?Sized> FmtWriter<'a, W> {
+    /// Write `value`, as formatted by its [`Display`] implementation, to `writer`
+    pub(crate) fn write_display<T>(writer: &'a mut W, value: &T) -> Result<(), MqttSerializeError>
+    where
+        T: Display + ?Sized,
+    {
+        let mut fmt_writer = Self {
+            writer,
+            error: None,
+        };
+        match write!(fmt_writer, "{}", value) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(fmt_writer
+                .error
+                .unwrap_or(MqttSerializeError::CapacityExceeded)),
+        }
+    }
+}
+
+impl<W: PayloadWriter + ?Sized> Write for FmtWriter<'_, W> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.writer.write_bytes(s.as_bytes()).map_err(|e| {
+            self.error = Some(e);
+            core::fmt::Error
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PayloadWriter, SliceWriter, TopicWriter};
+    use crate::{MqttSerializeError, Topic, TopicError};
+
+    fn is_capacity_exceeded<T>(result: Result<T, MqttSerializeError>) -> bool {
+        matches!(result, Err(MqttSerializeError::CapacityExceeded))
+    }
+
+    #[test]
+    fn push_layer() {
+        let mut topic = Topic::new();
+        topic.push_layer("device").unwrap();
+        topic.push_layer(&12).unwrap();
+        assert_eq!(topic.str(), "device/12");
+
+        // Invalid layers leave the topic unchanged
+        assert!(matches!(
+            topic.push_layer("a/b"),
+            Err(MqttSerializeError::Topic(TopicError::LayerSeparator(layer))) if layer == "a/b"
+        ));
+        assert!(matches!(
+            topic.push_layer("+"),
+            Err(MqttSerializeError::Topic(TopicError::InvalidCharacter('+')))
+        ));
+        assert_eq!(topic.str(), "device/12");
+    }
+
+    #[test]
+    fn slice_writer() {
+        let mut buffer = [0; 4];
+        let mut writer = SliceWriter::new(&mut buffer);
+        writer.write_bytes(b"abc").unwrap();
+        assert!(is_capacity_exceeded(writer.write_bytes(b"de")));
+        writer.write_bytes(b"d").unwrap();
+        assert_eq!(writer.written(), b"abcd");
+    }
+
+    #[test]
+    fn write_in_place() {
+        fn write_abc(buffer: &mut [u8]) -> Result<usize, MqttSerializeError> {
+            let bytes = buffer
+                .get_mut(..3)
+                .ok_or(MqttSerializeError::CapacityExceeded)?;
+            bytes.copy_from_slice(b"abc");
+            Ok(3)
+        }
+
+        let mut buffer = [0; 5];
+        let mut writer = SliceWriter::new(&mut buffer);
+        writer.write_in_place(&mut write_abc).unwrap().unwrap();
+        assert!(is_capacity_exceeded(
+            writer.write_in_place(&mut write_abc).unwrap()
+        ));
+        assert_eq!(writer.written(), b"abc");
+
+        let mut payload = Vec::new();
+        payload.write_in_place(&mut write_abc).unwrap().unwrap();
+        assert_eq!(payload, b"abc");
+    }
+
+    #[cfg(feature = "heapless")]
+    #[test]
+    fn heapless_writers() {
+        let mut topic = heapless::String::<8>::new();
+        topic.push_layer("device").unwrap();
+        assert!(is_capacity_exceeded(topic.push_layer("12")));
+        assert_eq!(topic.as_str(), "device");
+        topic.push_layer("1").unwrap();
+        assert_eq!(topic.as_str(), "device/1");
+
+        let mut payload = heapless::Vec::<u8, 2>::new();
+        payload.write_bytes(b"ab").unwrap();
+        assert!(is_capacity_exceeded(payload.write_bytes(b"c")));
+
+        let mut payload = heapless::Vec::<u8, 4>::new();
+        payload.write_bytes(b"a").unwrap();
+        payload
+            .write_in_place(&mut |buffer| {
+                buffer[..2].copy_from_slice(b"bc");
+                Ok(2)
+            })
+            .unwrap()
+            .unwrap();
+        assert!(is_capacity_exceeded(
+            payload
+                .write_in_place(&mut |_| Err(MqttSerializeError::CapacityExceeded))
+                .unwrap()
+        ));
+        assert_eq!(payload, b"abc");
+    }
+}
@@ -0,0 +1,24 @@
+use mqtt_macro::MqttItem;
+
+#[derive(MqttItem)]
+#[mqtt_item(topic = "device/<id>")]
+struct Device {
+    id: u32,
+}
+
+// Not marked with `by_ref`, so it does not implement `ToTopicAndPayload`
+#[derive(MqttItem)]
+#[mqtt_item(topic = "gateway/<device>")]
+struct Gateway {
+    #[mqtt_item(layer)]
+    device: Device,
+}
+
+#[derive(MqttItem)]
+#[mqtt_item(topic = "site/<gateway>", by_ref)]
+struct Site {
+    #[mqtt_item(layer)]
+    gateway: Gateway,
+}
+
+fn main() {}
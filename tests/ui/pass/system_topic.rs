@@ -0,0 +1,18 @@
+use mqtt_macro::MqttItem;
+
+#[derive(MqttItem)]
+#[mqtt_item(topic = "$SYS/broker/uptime", payload = "<seconds>", system_topic)]
+struct Uptime {
+    seconds: u64,
+}
+
+#[derive(MqttItem)]
+#[mqtt_item(system_topic)]
+enum Shadow {
+    #[mqtt_item(topic = "$aws/things/<thing>/shadow/update", payload = "<state>")]
+    Update { thing: String, state: String },
+    #[mqtt_item(topic = "$aws/things/<thing>/shadow/delete")]
+    Delete { thing: String },
+}
+
+fn main() {}
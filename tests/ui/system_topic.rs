@@ -0,0 +1,9 @@
+use mqtt_macro::MqttItem;
+
+#[derive(MqttItem)]
+#[mqtt_item(topic = "$SYS/broker/uptime", payload = "<seconds>")]
+struct Uptime {
+    seconds: u64,
+}
+
+fn main() {}
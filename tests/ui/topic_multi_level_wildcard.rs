@@ -0,0 +1,11 @@
+use mqtt_macro::MqttItem;
+
+#[derive(MqttItem)]
+enum Message {
+    #[mqtt_item(topic = "device/<id>/temp")]
+    Temperature { id: u32 },
+    #[mqtt_item(topic = "device/<id>/#")]
+    All { id: u32 },
+}
+
+fn main() {}
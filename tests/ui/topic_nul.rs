@@ -0,0 +1,9 @@
+use mqtt_macro::MqttItem;
+
+#[derive(MqttItem)]
+#[mqtt_item(topic = "device/<id>/te\0mp")]
+struct Temperature {
+    id: u32,
+}
+
+fn main() {}
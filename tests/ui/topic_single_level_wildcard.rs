@@ -0,0 +1,9 @@
+use mqtt_macro::MqttItem;
+
+#[derive(MqttItem)]
+#[mqtt_item(topic = "device/+/temp", payload = "<value>")]
+struct Temperature {
+    value: f32,
+}
+
+fn main() {}